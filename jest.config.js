@@ -1,6 +1,6 @@
 module.exports = {
     testEnvironment: 'node',
-    roots: ['<rootDir>/src/backend'],
+    roots: ['<rootDir>/src/authentication', '<rootDir>/src/backend', '<rootDir>/src/webhook_delivery'],
     testMatch: ['**/*.test.ts'],
     transform: {
       '^.+\\.tsx?$': 'ts-jest'
@@ -0,0 +1,3 @@
+CREATE TABLE refresh_tokens (id SERIAL PRIMARY KEY,token_hash TEXT NOT NULL UNIQUE,family_id TEXT NOT NULL,username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,expires_at TIMESTAMP NOT NULL,rotated_at TIMESTAMP,revoked_at TIMESTAMP,created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+
+CREATE INDEX idx_refresh_tokens_family_id ON refresh_tokens(family_id);
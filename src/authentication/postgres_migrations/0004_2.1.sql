@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN roles TEXT[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE users ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';
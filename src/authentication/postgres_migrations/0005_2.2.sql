@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE users ALTER COLUMN email_verified SET DEFAULT FALSE;
@@ -0,0 +1,3 @@
+CREATE TABLE password_reset_tokens (id SERIAL PRIMARY KEY,token_hash TEXT NOT NULL UNIQUE,username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,expires_at TIMESTAMP NOT NULL,used_at TIMESTAMP,created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+
+CREATE INDEX idx_password_reset_tokens_username ON password_reset_tokens(username);
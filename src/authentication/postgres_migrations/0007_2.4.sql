@@ -0,0 +1,5 @@
+CREATE TABLE login_attempts (attempt_key TEXT PRIMARY KEY,failures INTEGER NOT NULL,last_failure_at TIMESTAMP NOT NULL,locked_until TIMESTAMP);
+
+CREATE TABLE login_lockouts (id SERIAL PRIMARY KEY,attempt_key TEXT NOT NULL,username TEXT NOT NULL,client_ip TEXT,failures INTEGER NOT NULL,locked_until TIMESTAMP NOT NULL,created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+
+CREATE INDEX idx_login_lockouts_username ON login_lockouts(username);
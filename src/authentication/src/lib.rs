@@ -1,52 +1,82 @@
-use std::sync::Arc;
-
-use auth::AuthenticationService;
-use serde::{Deserialize, Serialize};
-use tokio_postgres::{Error, NoTls};
-use tracing_subscriber::{
-    fmt::{format::Pretty, time::UtcTime},
-    prelude::*,
+use lockout::LoginAttemptRepository;
+use passwords::{
+    ForgotPasswordCommand, PasswordResetError, PasswordResetRepository, ResetPasswordCommand,
 };
-use tracing_web::{performance_layer, MakeConsoleWriter};
-use users::{LoginCommand, RegisterCommand, UserRepository};
+use sessions::{LogoutCommand, RefreshCommand, SessionError, SessionRepository};
+use shared::{
+    auth::{AuthenticationService, Claims, JwkSet, SigningKey, JWKS_PATH},
+    errors::{finalize_response, request_id, set_panic_hook, ApiError},
+    revocation::RevocationStore,
+    telemetry,
+};
+use std::rc::Rc;
+use users::{LoginCommand, RegisterCommand, UserErrors, UserRepository, VerifyEmailCommand};
+use validation::PasswordPolicy;
 use worker::{postgres_tls::PassthroughTls, *};
 
-mod auth;
+mod lockout;
+mod passwords;
+mod sessions;
 mod users;
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    pub sub: String,
-    pub exp: usize,
-}
+mod validation;
 
 #[event(start)]
 fn start() {
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .json()
-        .with_ansi(false) // Only partially supported across JavaScript runtimes
-        .with_timer(UtcTime::rfc_3339()) // std::time is not available in browsers
-        .with_writer(MakeConsoleWriter); // write events to the console
-
-    let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
-
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(perf_layer)
-        .init();
+    telemetry::init();
 }
 
 pub struct AppState {
     user_repository: UserRepository,
+    session_repository: SessionRepository,
+    password_reset_repository: PasswordResetRepository,
+    login_attempt_repository: LoginAttemptRepository,
+    password_policy: PasswordPolicy,
     auth_service: AuthenticationService,
+    revocation_store: RevocationStore,
+    request_id: String,
+}
+
+impl AppState {
+    // Every error a handler returns goes through here, so it carries the request ID.
+    fn error(&self, error: ApiError) -> Result<Response> {
+        error.into_response(&self.request_id)
+    }
 }
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    console_error_panic_hook::set_once();
-    let jwt_secret = env.secret("JWT_SECRET")?.to_string();
+    set_panic_hook();
+
+    let request_id = request_id(&req);
+    let result = route(req, env, request_id.clone()).await;
+
+    finalize_response(result, &request_id).await
+}
+
+async fn route(req: Request, env: Env, request_id: String) -> Result<Response> {
+    // JWT_PUBLIC_KEYS lists every key tokens may be verified with, JWT_SIGNING_KEY_ID picks the
+    // one new tokens are signed with.
+    let key_set: JwkSet = serde_json::from_str(&env.var("JWT_PUBLIC_KEYS")?.to_string())
+        .map_err(|e| worker::Error::RustError(format!("Failed to parse public keys: {}", e)))?;
+    let signing_key = SigningKey::from_pem(
+        env.var("JWT_SIGNING_KEY_ID")?.to_string(),
+        &env.secret("JWT_SIGNING_KEY")?.to_string(),
+        &key_set,
+    )
+    .map_err(|e| worker::Error::RustError(e.to_string()))?;
+
+    let auth_service = AuthenticationService::issuer(signing_key, key_set);
+
+    // The other workers cannot verify any token without these keys, so they are served before
+    // the database is connected to and stay available while it is down.
+    if req.method() == Method::Get && req.path() == JWKS_PATH {
+        return jwks_response(&auth_service, &request_id);
+    }
 
     let user_notifications_queue = env.queue("USER_NOTIFICATIONS")?;
 
+    let revoked_tokens = env.kv("REVOKED_TOKENS")?;
+
     let hyperdrive = env.hyperdrive("DB")?;
 
     let config = hyperdrive
@@ -57,14 +87,26 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     let host = hyperdrive.host();
     let port = hyperdrive.port();
 
-    let socket = Socket::builder()
+    let connected = match Socket::builder()
         .secure_transport(SecureTransport::StartTls)
-        .connect(host, port)?;
+        .connect(host, port)
+    {
+        Ok(socket) => config
+            .connect_raw(socket, PassthroughTls)
+            .await
+            .map_err(|e| format!("{:?}", e)),
+        Err(e) => Err(e.to_string()),
+    };
 
-    let (client, connection) = config
-        .connect_raw(socket, PassthroughTls)
-        .await
-        .map_err(|e| worker::Error::RustError(format!("Failed to connect: {:?}", e)))?;
+    // Every route needs the database, so without it the worker can only ask clients to retry.
+    let (client, connection) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            tracing::error!("Failed to connect: {}", e);
+            return ApiError::unavailable("Service is temporarily unavailable, try again later")
+                .into_response(&request_id);
+        }
+    };
 
     wasm_bindgen_futures::spawn_local(async move {
         if let Err(error) = connection.await {
@@ -72,12 +114,25 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         }
     });
 
+    let client = Rc::new(client);
+
     Router::with_data(AppState {
-        user_repository: UserRepository::new(client, user_notifications_queue),
-        auth_service: AuthenticationService::new(jwt_secret),
+        user_repository: UserRepository::new(client.clone(), user_notifications_queue),
+        session_repository: SessionRepository::new(client.clone()),
+        password_reset_repository: PasswordResetRepository::new(client.clone()),
+        login_attempt_repository: LoginAttemptRepository::new(client),
+        password_policy: PasswordPolicy::from_env(&env),
+        auth_service,
+        revocation_store: RevocationStore::new(revoked_tokens),
+        request_id,
     })
     .post_async("/api/register", handle_register)
     .post_async("/api/login", handle_login)
+    .get_async("/api/verify-email", handle_verify_email)
+    .post_async("/api/password/forgot", handle_forgot_password)
+    .post_async("/api/password/reset", handle_reset_password)
+    .post_async("/api/token/refresh", handle_refresh_token)
+    .post_async("/api/logout", handle_logout)
     .run(req, env)
     .await
 }
@@ -85,30 +140,265 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
 pub async fn handle_register(mut req: Request, ctx: RouteContext<AppState>) -> Result<Response> {
     tracing::info!("Handle register");
 
-    let command: RegisterCommand = req.json().await?;
+    let command: RegisterCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    // The link in the verification email points back at this worker, through the same host the
+    // user registered with.
+    let mut verification_url = req.url()?;
+    verification_url.set_path("/api/verify-email");
 
-    match command.handle(&ctx.data.user_repository).await {
+    match command
+        .handle(
+            &ctx.data.user_repository,
+            &ctx.data.auth_service,
+            &ctx.data.password_policy,
+            verification_url,
+        )
+        .await
+    {
         Ok(user_result) => Response::from_json(&user_result),
-        Err(e) => match e {
-            users::UserErrors::UnknownFailure => Response::error("Failed to register user", 500),
-            users::UserErrors::Exists => Response::error("User exists ", 400),
-            users::UserErrors::InvalidPassword => Response::error("Failed to register user", 500),
-        },
+        Err(e) => user_error(&ctx, e),
     }
 }
 
 pub async fn handle_login(mut req: Request, ctx: RouteContext<AppState>) -> Result<Response> {
-    let command: LoginCommand = req.json().await?;
+    let command: LoginCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    // Set by Cloudflare to the address the request came from, failures are counted against it.
+    let client_ip = req.headers().get("CF-Connecting-IP")?;
+
+    match command
+        .handle(
+            &ctx.data.user_repository,
+            &ctx.data.session_repository,
+            &ctx.data.auth_service,
+            &ctx.data.login_attempt_repository,
+            client_ip.as_deref(),
+        )
+        .await
+    {
+        Ok(resp) => Response::from_json(&resp),
+        Err(e) => user_error(&ctx, e),
+    }
+}
+
+fn user_error(ctx: &RouteContext<AppState>, e: UserErrors) -> Result<Response> {
+    match &e {
+        UserErrors::Unavailable(_) | UserErrors::UnknownFailure => tracing::error!("{}", e),
+        _ => tracing::info!("{}", e),
+    }
+
+    let retry_after = match e {
+        UserErrors::LockedOut(retry_after) => Some(retry_after),
+        _ => None,
+    };
+
+    let mut response = ctx.data.error(e.into())?;
+
+    if let Some(retry_after) = retry_after {
+        response
+            .headers_mut()
+            .set("Retry-After", &retry_after.to_string())?;
+    }
+
+    Ok(response)
+}
+
+// Opened from the link in the verification email, so it sends the browser on to the login page
+// rather than returning JSON.
+pub async fn handle_verify_email(req: Request, ctx: RouteContext<AppState>) -> Result<Response> {
+    let command: VerifyEmailCommand = match req.query() {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Missing verification token"))
+        }
+    };
 
     match command
         .handle(&ctx.data.user_repository, &ctx.data.auth_service)
         .await
+    {
+        Ok(_) => {
+            let mut login_url = req.url()?;
+            login_url.set_path("/login/");
+            login_url.set_query(Some("email_verified=true"));
+
+            Response::redirect(login_url)
+        }
+        Err(e) => user_error(&ctx, e),
+    }
+}
+
+pub async fn handle_forgot_password(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> Result<Response> {
+    let command: ForgotPasswordCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    // The emailed link opens the reset page on the site the request came through.
+    let mut reset_url = req.url()?;
+    reset_url.set_path("/password/");
+
+    // Failures are only logged, the response must not say whether the user exists.
+    if let Err(e) = command
+        .handle(
+            &ctx.data.user_repository,
+            &ctx.data.password_reset_repository,
+            reset_url,
+        )
+        .await
+    {
+        tracing::error!("{}", e);
+    }
+
+    Response::from_json(&serde_json::json!({
+        "message": "If the account exists, a link to reset its password has been emailed"
+    }))
+}
+
+pub async fn handle_reset_password(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> Result<Response> {
+    let command: ResetPasswordCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    match command
+        .handle(
+            &ctx.data.user_repository,
+            &ctx.data.password_reset_repository,
+            &ctx.data.session_repository,
+            &ctx.data.revocation_store,
+            &ctx.data.password_policy,
+        )
+        .await
+    {
+        Ok(_) => Ok(Response::empty()?.with_status(204)),
+        Err(PasswordResetError::InvalidToken) => ctx.data.error(ApiError::bad_request(
+            "Reset link is invalid or has expired",
+        )),
+        Err(PasswordResetError::InvalidPassword(message)) => ctx.data.error(
+            ApiError::validation("Password is invalid").with_field_error("password", message),
+        ),
+        Err(e) => {
+            tracing::error!("{}", e);
+            ctx.data.error(ApiError::internal())
+        }
+    }
+}
+
+pub async fn handle_refresh_token(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+) -> Result<Response> {
+    let command: RefreshCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    match command
+        .handle(&ctx.data.session_repository, &ctx.data.auth_service)
+        .await
     {
         Ok(resp) => Response::from_json(&resp),
-        Err(e) => match e {
-            users::UserErrors::InvalidPassword => Response::error("Unauthorized", 401),
-            users::UserErrors::Exists => Response::error("User exists ", 400),
-            users::UserErrors::UnknownFailure => Response::error("Failed ", 500),
-        },
+        Err(SessionError::InvalidToken) | Err(SessionError::Reused) => {
+            ctx.data.error(ApiError::unauthorized())
+        }
+        Err(e) => {
+            tracing::error!("{}", e);
+            ctx.data.error(ApiError::internal())
+        }
+    }
+}
+
+pub async fn handle_logout(mut req: Request, ctx: RouteContext<AppState>) -> Result<Response> {
+    let claims = match bearer_claims(&req, &ctx.data.auth_service).await {
+        Some(claims) => claims,
+        None => return ctx.data.error(ApiError::unauthorized()),
+    };
+
+    // The body is optional, a bare request only revokes the access token.
+    let body = req.text().await?;
+    let command: LogoutCommand = if body.trim().is_empty() {
+        LogoutCommand::default()
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(command) => command,
+            Err(_) => {
+                return ctx
+                    .data
+                    .error(ApiError::bad_request("Request body is not valid JSON"))
+            }
+        }
+    };
+
+    match command
+        .handle(
+            &claims,
+            &ctx.data.session_repository,
+            &ctx.data.revocation_store,
+        )
+        .await
+    {
+        Ok(_) => Ok(Response::empty()?.with_status(204)),
+        Err(e) => {
+            tracing::error!("{}", e);
+            ctx.data.error(ApiError::internal())
+        }
     }
 }
+
+// The other workers fetch these keys over their service binding to verify tokens themselves.
+fn jwks_response(auth_service: &AuthenticationService, request_id: &str) -> Result<Response> {
+    let key_set = match auth_service.published_keys() {
+        Some(key_set) => key_set,
+        None => return ApiError::internal().into_response(request_id),
+    };
+
+    let mut response = Response::from_json(key_set)?;
+    response
+        .headers_mut()
+        .set("Cache-Control", "public, max-age=300")?;
+
+    Ok(response)
+}
+
+async fn bearer_claims(req: &Request, auth_service: &AuthenticationService) -> Option<Claims> {
+    let header = req.headers().get("Authorization").ok()??;
+    let token = header.strip_prefix("Bearer ")?;
+
+    auth_service.verify_jwt_token(token).await.ok()
+}
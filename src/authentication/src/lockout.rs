@@ -0,0 +1,252 @@
+use std::rc::Rc;
+
+use tokio_postgres::{types::Type, Client};
+
+// Failures are forgotten once there has been none for this long.
+const ATTEMPT_WINDOW_IN_SECONDS: i64 = 60 * 60;
+const LOCKOUT_IN_SECONDS: i64 = 15 * 60;
+
+// Once past `backoff_after` failures each further failure doubles how long the key is locked
+// for, starting at a second. From `lockout_after` failures it is locked for the full lockout,
+// which is recorded in the audit table. Many users can share an IP, so it gets more slack than
+// a username.
+struct AttemptPolicy {
+    prefix: &'static str,
+    backoff_after: i32,
+    lockout_after: i32,
+}
+
+const USERNAME_POLICY: AttemptPolicy = AttemptPolicy {
+    prefix: "user",
+    backoff_after: 3,
+    lockout_after: 10,
+};
+
+const CLIENT_IP_POLICY: AttemptPolicy = AttemptPolicy {
+    prefix: "ip",
+    backoff_after: 20,
+    lockout_after: 100,
+};
+
+impl AttemptPolicy {
+    fn key(&self, value: &str) -> String {
+        format!("{}:{}", self.prefix, value)
+    }
+
+    fn lock_in_seconds(&self, failures: i32) -> Option<i64> {
+        if failures >= self.lockout_after {
+            return Some(LOCKOUT_IN_SECONDS);
+        }
+
+        if failures < self.backoff_after {
+            return None;
+        }
+
+        let doublings = (failures - self.backoff_after).min(16) as u32;
+        Some((1_i64 << doublings).min(LOCKOUT_IN_SECONDS))
+    }
+}
+
+// Counts failed logins per username and per client IP, whether or not the username exists, so
+// a lockout says nothing about which accounts are real.
+pub struct LoginAttemptRepository {
+    client: Rc<Client>,
+}
+
+impl LoginAttemptRepository {
+    pub fn new(client: Rc<Client>) -> Self {
+        Self { client }
+    }
+
+    fn keys(username: &str, client_ip: Option<&str>) -> Vec<(&'static AttemptPolicy, String)> {
+        let mut keys = vec![(&USERNAME_POLICY, USERNAME_POLICY.key(username))];
+
+        if let Some(client_ip) = client_ip {
+            keys.push((&CLIENT_IP_POLICY, CLIENT_IP_POLICY.key(client_ip)));
+        }
+
+        keys
+    }
+
+    /// How many seconds until the username or IP may try again, if either is locked.
+    pub async fn locked_for(
+        &self,
+        username: &str,
+        client_ip: Option<&str>,
+    ) -> Result<Option<i64>, tokio_postgres::Error> {
+        let keys: Vec<String> = Self::keys(username, client_ip)
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect();
+
+        let rows = self
+            .client
+            .query_typed(
+                "SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - NOW()))::BIGINT
+FROM login_attempts
+WHERE attempt_key = ANY($1) AND locked_until > NOW()",
+                &[(&keys, Type::TEXT_ARRAY)],
+            )
+            .await?;
+
+        Ok(rows.first().and_then(|row| row.get::<_, Option<i64>>(0)))
+    }
+
+    pub async fn record_failure(&self, username: &str, client_ip: Option<&str>) {
+        for (policy, key) in Self::keys(username, client_ip) {
+            if let Err(e) = self.add_failure(policy, &key, username, client_ip).await {
+                tracing::error!("Failure recording failed login: {}", e);
+            }
+        }
+    }
+
+    async fn add_failure(
+        &self,
+        policy: &AttemptPolicy,
+        key: &str,
+        username: &str,
+        client_ip: Option<&str>,
+    ) -> Result<(), tokio_postgres::Error> {
+        let rows = self
+            .client
+            .query_typed(
+                "INSERT INTO login_attempts (attempt_key, failures, last_failure_at)
+VALUES ($1, 1, NOW())
+ON CONFLICT (attempt_key) DO UPDATE SET
+failures = CASE
+    WHEN login_attempts.last_failure_at < NOW() - $2 * INTERVAL '1 second' THEN 1
+    ELSE login_attempts.failures + 1
+END,
+last_failure_at = NOW()
+RETURNING failures",
+                &[(&key, Type::TEXT), (&ATTEMPT_WINDOW_IN_SECONDS, Type::INT8)],
+            )
+            .await?;
+
+        let failures: i32 = match rows.first() {
+            Some(row) => row.get(0),
+            None => return Ok(()),
+        };
+
+        let lock_in_seconds = match policy.lock_in_seconds(failures) {
+            Some(lock_in_seconds) => lock_in_seconds,
+            None => return Ok(()),
+        };
+
+        self.client
+            .query_typed(
+                "UPDATE login_attempts SET locked_until = NOW() + $2 * INTERVAL '1 second'
+WHERE attempt_key = $1",
+                &[(&key, Type::TEXT), (&lock_in_seconds, Type::INT8)],
+            )
+            .await?;
+
+        if failures >= policy.lockout_after {
+            tracing::warn!("Locking out {} after {} failed logins", key, failures);
+
+            self.client
+                .query_typed(
+                    "INSERT INTO login_lockouts (attempt_key, username, client_ip, failures, locked_until)
+VALUES ($1, $2, $3, $4, NOW() + $5 * INTERVAL '1 second')",
+                    &[
+                        (&key, Type::TEXT),
+                        (&username, Type::TEXT),
+                        (&client_ip, Type::TEXT),
+                        (&failures, Type::INT4),
+                        (&lock_in_seconds, Type::INT8),
+                    ],
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Clears the username's failures. The IP's are left to expire, so one account an attacker
+    /// controls cannot be used to reset them.
+    pub async fn record_success(&self, username: &str) {
+        let result = self
+            .client
+            .query_typed(
+                "DELETE FROM login_attempts WHERE attempt_key = $1",
+                &[(&USERNAME_POLICY.key(username), Type::TEXT)],
+            )
+            .await;
+
+        if let Err(e) = result {
+            tracing::error!("Failure clearing failed logins: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_not_locked_before_the_backoff_starts() {
+        for failures in 0..USERNAME_POLICY.backoff_after {
+            assert_eq!(USERNAME_POLICY.lock_in_seconds(failures), None);
+        }
+    }
+
+    #[test]
+    fn each_failure_past_the_backoff_doubles_the_lock() {
+        assert_eq!(USERNAME_POLICY.lock_in_seconds(3), Some(1));
+        assert_eq!(USERNAME_POLICY.lock_in_seconds(4), Some(2));
+        assert_eq!(USERNAME_POLICY.lock_in_seconds(5), Some(4));
+        assert_eq!(USERNAME_POLICY.lock_in_seconds(9), Some(64));
+    }
+
+    #[test]
+    fn usernames_are_locked_out_from_the_lockout_threshold() {
+        assert_eq!(USERNAME_POLICY.lock_in_seconds(10), Some(900));
+        assert_eq!(
+            USERNAME_POLICY.lock_in_seconds(1000),
+            Some(LOCKOUT_IN_SECONDS)
+        );
+    }
+
+    #[test]
+    fn client_ips_get_more_slack_than_usernames() {
+        assert_eq!(CLIENT_IP_POLICY.lock_in_seconds(19), None);
+        assert_eq!(CLIENT_IP_POLICY.lock_in_seconds(20), Some(1));
+        assert_eq!(
+            CLIENT_IP_POLICY.lock_in_seconds(99),
+            Some(LOCKOUT_IN_SECONDS)
+        );
+        assert_eq!(
+            CLIENT_IP_POLICY.lock_in_seconds(100),
+            Some(LOCKOUT_IN_SECONDS)
+        );
+    }
+
+    #[test]
+    fn the_backoff_never_exceeds_the_lockout() {
+        let policy = AttemptPolicy {
+            prefix: "test",
+            backoff_after: 1,
+            lockout_after: i32::MAX,
+        };
+
+        for failures in 1..100 {
+            assert!(policy.lock_in_seconds(failures).unwrap() <= LOCKOUT_IN_SECONDS);
+        }
+    }
+
+    #[test]
+    fn failures_are_counted_per_username_and_client_ip() {
+        let keys = |client_ip| -> Vec<String> {
+            LoginAttemptRepository::keys("jane", client_ip)
+                .into_iter()
+                .map(|(_, key)| key)
+                .collect()
+        };
+
+        assert_eq!(keys(None), vec!["user:jane"]);
+        assert_eq!(
+            keys(Some("203.0.113.7")),
+            vec!["user:jane", "ip:203.0.113.7"]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::rc::Rc;
+
+use serde::Deserialize;
+use shared::revocation::RevocationStore;
+use thiserror::Error;
+use tokio_postgres::{types::Type, Client};
+use worker::Url;
+
+use crate::{
+    sessions::{generate_opaque_token, hash_token, SessionError, SessionRepository},
+    users::UserRepository,
+    validation::PasswordPolicy,
+};
+
+// A reset link has to be used within an hour of being requested.
+const RESET_TOKEN_LIFETIME_IN_SECONDS: i64 = 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum PasswordResetError {
+    #[error("Reset token is invalid, expired or has already been used")]
+    InvalidToken,
+    #[error("Password {0}")]
+    InvalidPassword(String),
+    #[error("Failure resetting password: {0}")]
+    Storage(String),
+}
+
+impl From<tokio_postgres::Error> for PasswordResetError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        PasswordResetError::Storage(e.to_string())
+    }
+}
+
+impl From<worker::Error> for PasswordResetError {
+    fn from(e: worker::Error) -> Self {
+        PasswordResetError::Storage(e.to_string())
+    }
+}
+
+impl From<SessionError> for PasswordResetError {
+    fn from(e: SessionError) -> Self {
+        PasswordResetError::Storage(e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordCommand {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordCommand {
+    token: String,
+    password: String,
+}
+
+impl ForgotPasswordCommand {
+    /// Emails the user a link to `reset_url` if they exist. Callers respond the same way either
+    /// way, so usernames cannot be discovered through it.
+    pub async fn handle(
+        &self,
+        user_repository: &UserRepository,
+        reset_repository: &PasswordResetRepository,
+        mut reset_url: Url,
+    ) -> Result<(), PasswordResetError> {
+        let email_address = match user_repository.email_address_for(&self.username).await? {
+            Some(email_address) => email_address,
+            None => {
+                tracing::info!("Password reset requested for an unknown user");
+                return Ok(());
+            }
+        };
+
+        let token = reset_repository.add_reset_token(&self.username).await?;
+
+        reset_url.set_query(None);
+        reset_url.query_pairs_mut().append_pair("token", &token);
+
+        user_repository
+            .send_password_reset_email(&self.username, &email_address, reset_url.to_string())
+            .await;
+
+        Ok(())
+    }
+}
+
+impl ResetPasswordCommand {
+    pub async fn handle(
+        &self,
+        user_repository: &UserRepository,
+        reset_repository: &PasswordResetRepository,
+        session_repository: &SessionRepository,
+        revocation_store: &RevocationStore,
+        password_policy: &PasswordPolicy,
+    ) -> Result<(), PasswordResetError> {
+        if let Some(message) = password_policy.check(&self.password) {
+            return Err(PasswordResetError::InvalidPassword(message));
+        }
+
+        let username = reset_repository
+            .redeem(&self.token)
+            .await?
+            .ok_or(PasswordResetError::InvalidToken)?;
+
+        user_repository
+            .set_password(&username, &self.password)
+            .await?;
+
+        // Whoever knew the old password may still be signed in with it.
+        session_repository.end_all_sessions(&username).await?;
+        revocation_store.revoke_all_for(&username).await?;
+
+        Ok(())
+    }
+}
+
+// Reset tokens are stored hashed, like refresh tokens, and can only be redeemed once.
+pub struct PasswordResetRepository {
+    client: Rc<Client>,
+}
+
+impl PasswordResetRepository {
+    pub fn new(client: Rc<Client>) -> Self {
+        Self { client }
+    }
+
+    async fn add_reset_token(&self, username: &str) -> Result<String, PasswordResetError> {
+        let token = generate_opaque_token();
+
+        self.client
+            .query_typed(
+                "INSERT INTO password_reset_tokens (token_hash, username, expires_at)
+VALUES ($1, $2, NOW() + $3 * INTERVAL '1 second')",
+                &[
+                    (&hash_token(&token), Type::TEXT),
+                    (&username, Type::TEXT),
+                    (&RESET_TOKEN_LIFETIME_IN_SECONDS, Type::INT8),
+                ],
+            )
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Marks the token as used, returning the user it was issued to if it was still valid. Any
+    /// other links the user was sent stop working too.
+    async fn redeem(&self, token: &str) -> Result<Option<String>, PasswordResetError> {
+        let rows = self
+            .client
+            .query_typed(
+                "UPDATE password_reset_tokens SET used_at = NOW()
+WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+RETURNING username",
+                &[(&hash_token(token), Type::TEXT)],
+            )
+            .await?;
+
+        let username: String = match rows.first() {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+
+        self.client
+            .query_typed(
+                "UPDATE password_reset_tokens SET used_at = NOW()
+WHERE username = $1 AND used_at IS NULL",
+                &[(&username, Type::TEXT)],
+            )
+            .await?;
+
+        Ok(Some(username))
+    }
+}
@@ -0,0 +1,375 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::{
+    auth::{AuthenticationService, Claims, TokenError, ACCESS_TOKEN_LIFETIME_IN_SECONDS},
+    revocation::RevocationStore,
+};
+use thiserror::Error;
+use tokio_postgres::{types::Type, Client};
+use uuid::Uuid;
+
+// Every refresh pushes the expiry back, so a session only ends after this long without use.
+const REFRESH_TOKEN_LIFETIME_IN_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Refresh token is invalid or expired")]
+    InvalidToken,
+    #[error("Refresh token has already been used")]
+    Reused,
+    #[error(transparent)]
+    Token(#[from] TokenError),
+    #[error("Failure storing session: {0}")]
+    Storage(String),
+}
+
+impl From<tokio_postgres::Error> for SessionError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        SessionError::Storage(e.to_string())
+    }
+}
+
+impl From<worker::Error> for SessionError {
+    fn from(e: worker::Error) -> Self {
+        SessionError::Storage(e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RefreshCommand {
+    refresh_token: String,
+}
+
+// Logging out revokes the access token it was sent with, and the session of the refresh token
+// if one is given. With `all_sessions` every token issued to the user is revoked instead.
+#[derive(Deserialize, Default)]
+pub struct LogoutCommand {
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    all_sessions: bool,
+}
+
+// Returned on login and on every refresh. The refresh token is opaque and single use, the
+// client must replace its copy with the one in each response.
+#[derive(Serialize)]
+pub struct TokenResponse {
+    token: String,
+    refresh_token: String,
+    expires_in: usize,
+}
+
+// Who a token is issued to, as read from their user record.
+pub struct TokenSubject {
+    pub username: String,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+// A stored refresh token as it was when it was presented.
+struct StoredRefreshToken {
+    rotated: bool,
+    revoked: bool,
+    expired: bool,
+}
+
+impl StoredRefreshToken {
+    /// Whether the token may be swapped for the next one in its family. A token that has already
+    /// been rotated must have been copied, so it is reported as reused.
+    fn check(&self) -> Result<(), SessionError> {
+        if self.revoked || self.expired {
+            return Err(SessionError::InvalidToken);
+        }
+
+        if self.rotated {
+            return Err(SessionError::Reused);
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// An unguessable token for the client to hold, only its hash is stored.
+pub(crate) fn generate_opaque_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+impl RefreshCommand {
+    pub async fn handle(
+        &self,
+        session_repository: &SessionRepository,
+        auth_service: &AuthenticationService,
+    ) -> Result<TokenResponse, SessionError> {
+        let (subject, refresh_token) = session_repository.rotate(&self.refresh_token).await?;
+
+        session_repository.token_response(auth_service, subject, refresh_token)
+    }
+}
+
+impl LogoutCommand {
+    pub async fn handle(
+        &self,
+        claims: &Claims,
+        session_repository: &SessionRepository,
+        revocation_store: &RevocationStore,
+    ) -> Result<(), SessionError> {
+        revocation_store.revoke_token(claims).await?;
+
+        if self.all_sessions {
+            revocation_store.revoke_all_for(&claims.sub).await?;
+            return session_repository.end_all_sessions(&claims.sub).await;
+        }
+
+        match &self.refresh_token {
+            Some(refresh_token) => {
+                session_repository
+                    .end_session(refresh_token, &claims.sub)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// Refresh tokens are stored hashed. Each login starts a new family and every refresh rotates
+// the token within it, so a token that is presented after it has been rotated must have been
+// copied, and the whole family is revoked.
+pub struct SessionRepository {
+    client: Rc<Client>,
+}
+
+impl SessionRepository {
+    pub fn new(client: Rc<Client>) -> Self {
+        Self { client }
+    }
+
+    /// Issues an access token along with the first refresh token of a new family.
+    pub async fn start_session(
+        &self,
+        auth_service: &AuthenticationService,
+        subject: TokenSubject,
+    ) -> Result<TokenResponse, SessionError> {
+        let family_id = Uuid::new_v4().to_string();
+        let refresh_token = self
+            .add_refresh_token(&subject.username, &family_id)
+            .await?;
+
+        self.token_response(auth_service, subject, refresh_token)
+    }
+
+    fn token_response(
+        &self,
+        auth_service: &AuthenticationService,
+        subject: TokenSubject,
+        refresh_token: String,
+    ) -> Result<TokenResponse, SessionError> {
+        let token =
+            auth_service.generate_token_for(subject.username, subject.roles, subject.scopes)?;
+
+        Ok(TokenResponse {
+            token,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_LIFETIME_IN_SECONDS,
+        })
+    }
+
+    async fn add_refresh_token(
+        &self,
+        username: &str,
+        family_id: &str,
+    ) -> Result<String, SessionError> {
+        let refresh_token = generate_opaque_token();
+
+        self.client
+            .query_typed(
+                "INSERT INTO refresh_tokens (token_hash, family_id, username, expires_at)
+VALUES ($1, $2, $3, NOW() + $4 * INTERVAL '1 second')",
+                &[
+                    (&hash_token(&refresh_token), Type::TEXT),
+                    (&family_id, Type::TEXT),
+                    (&username, Type::TEXT),
+                    (&REFRESH_TOKEN_LIFETIME_IN_SECONDS, Type::INT8),
+                ],
+            )
+            .await?;
+
+        Ok(refresh_token)
+    }
+
+    /// Swaps a refresh token for the next one in its family, returning the user it belongs to.
+    /// Roles and scopes are read again, so changes to them are picked up on the next refresh.
+    pub async fn rotate(
+        &self,
+        refresh_token: &str,
+    ) -> Result<(TokenSubject, String), SessionError> {
+        let token_hash = hash_token(refresh_token);
+
+        let rows = self
+            .client
+            .query_typed(
+                "SELECT refresh_tokens.username, family_id, rotated_at IS NOT NULL,
+revoked_at IS NOT NULL, expires_at < NOW(), users.roles, users.scopes
+FROM refresh_tokens
+JOIN users ON users.username = refresh_tokens.username
+WHERE token_hash = $1",
+                &[(&token_hash, Type::TEXT)],
+            )
+            .await?;
+
+        let row = rows.first().ok_or(SessionError::InvalidToken)?;
+        let username: String = row.get(0);
+        let family_id: String = row.get(1);
+        let stored = StoredRefreshToken {
+            rotated: row.get(2),
+            revoked: row.get(3),
+            expired: row.get(4),
+        };
+        let roles: Vec<String> = row.get(5);
+        let scopes: Vec<String> = row.get(6);
+
+        match stored.check() {
+            Err(SessionError::Reused) => return Err(self.revoke_family(&family_id).await),
+            result => result?,
+        }
+
+        // Two refreshes racing with the same token can both get this far, only one of them
+        // marks it as rotated.
+        let marked = self
+            .client
+            .query_typed(
+                "UPDATE refresh_tokens SET rotated_at = NOW()
+WHERE token_hash = $1 AND rotated_at IS NULL
+RETURNING id",
+                &[(&token_hash, Type::TEXT)],
+            )
+            .await?;
+
+        if marked.is_empty() {
+            return Err(self.revoke_family(&family_id).await);
+        }
+
+        let next_token = self.add_refresh_token(&username, &family_id).await?;
+
+        Ok((
+            TokenSubject {
+                username,
+                roles,
+                scopes,
+            },
+            next_token,
+        ))
+    }
+
+    async fn revoke_family(&self, family_id: &str) -> SessionError {
+        tracing::warn!("Refresh token reused, revoking token family {}", family_id);
+
+        let result = self
+            .client
+            .query_typed(
+                "UPDATE refresh_tokens SET revoked_at = NOW()
+WHERE family_id = $1 AND revoked_at IS NULL",
+                &[(&family_id, Type::TEXT)],
+            )
+            .await;
+
+        match result {
+            Ok(_) => SessionError::Reused,
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Revokes the family of the given refresh token, if it belongs to the user.
+    pub async fn end_session(
+        &self,
+        refresh_token: &str,
+        username: &str,
+    ) -> Result<(), SessionError> {
+        self.client
+            .query_typed(
+                "UPDATE refresh_tokens SET revoked_at = NOW()
+WHERE family_id = (SELECT family_id FROM refresh_tokens WHERE token_hash = $1 AND username = $2)
+AND revoked_at IS NULL",
+                &[
+                    (&hash_token(refresh_token), Type::TEXT),
+                    (&username, Type::TEXT),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn end_all_sessions(&self, username: &str) -> Result<(), SessionError> {
+        self.client
+            .query_typed(
+                "UPDATE refresh_tokens SET revoked_at = NOW()
+WHERE username = $1 AND revoked_at IS NULL",
+                &[(&username, Type::TEXT)],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(rotated: bool, revoked: bool, expired: bool) -> StoredRefreshToken {
+        StoredRefreshToken {
+            rotated,
+            revoked,
+            expired,
+        }
+    }
+
+    #[test]
+    fn an_unused_token_is_rotated() {
+        assert!(stored(false, false, false).check().is_ok());
+    }
+
+    #[test]
+    fn a_rotated_token_is_reused() {
+        assert!(matches!(
+            stored(true, false, false).check(),
+            Err(SessionError::Reused)
+        ));
+    }
+
+    #[test]
+    fn revoked_and_expired_tokens_are_invalid() {
+        for token in [
+            stored(false, true, false),
+            stored(false, false, true),
+            stored(false, true, true),
+        ] {
+            assert!(matches!(token.check(), Err(SessionError::InvalidToken)));
+        }
+    }
+
+    // Its family has already been revoked, so presenting it again revokes nothing more.
+    #[test]
+    fn a_rotated_token_from_a_revoked_family_is_invalid() {
+        assert!(matches!(
+            stored(true, true, false).check(),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn tokens_are_unique_and_stored_hashed() {
+        let token = generate_opaque_token();
+
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_opaque_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+    }
+}
@@ -1,29 +1,91 @@
-use crate::auth::AuthenticationService;
+use crate::{
+    lockout::LoginAttemptRepository,
+    sessions::{SessionError, SessionRepository, TokenResponse, TokenSubject},
+    validation::{check_email, check_username, field_errors, required, PasswordPolicy},
+};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
-use thiserror::Error;
-use tokio_postgres::{
-    types::{FromSql, Type},
-    Client,
+use shared::{
+    auth::AuthenticationService,
+    errors::{ApiError, ErrorCode, FieldError},
+    events::{UserDTO, UserNotification, UserNotificationTypes},
 };
+use std::rc::Rc;
+use thiserror::Error;
+use tokio_postgres::{error::SqlState, types::Type, Client};
 use tokio_postgres_utils::FromRow;
 use worker::*;
 
+// A bcrypt hash at the default cost of a password nobody has.
+const UNKNOWN_USER_PASSWORD_HASH: &str =
+    "$2b$12$xBqaGE61Fziu.0.driQqRuHsTPJeTrU8RwJg3Fb9CiCHG.UyuA9Mq";
+
 #[derive(Error, Debug)]
 pub enum UserErrors {
-    #[error("Exists")]
-    Exists,
-    #[error("Password is invalid")]
-    InvalidPassword,
-    #[error("Failure creating user")]
+    // Names the field, username or email, that is already taken.
+    #[error("A user with that {0} exists")]
+    Exists(&'static str),
+    // Used for both an unknown username and a wrong password, so a caller cannot tell which.
+    #[error("Username or password is incorrect")]
+    InvalidCredentials,
+    #[error("Email address has not been verified")]
+    EmailNotVerified,
+    #[error("Verification link is invalid or has expired")]
+    InvalidVerificationToken,
+    #[error("Too many failed logins, locked for {0} seconds")]
+    LockedOut(i64),
+    #[error("Request is invalid")]
+    Validation(Vec<FieldError>),
+    #[error("User store is unavailable: {0}")]
+    Unavailable(String),
+    #[error("Failure handling user request")]
     UnknownFailure,
 }
 
+impl From<tokio_postgres::Error> for UserErrors {
+    fn from(e: tokio_postgres::Error) -> Self {
+        UserErrors::Unavailable(e.to_string())
+    }
+}
+
+impl From<UserErrors> for ApiError {
+    fn from(e: UserErrors) -> Self {
+        match e {
+            UserErrors::Exists(field) => {
+                ApiError::conflict("User exists").with_field_error(field, "is already taken")
+            }
+            UserErrors::InvalidCredentials => ApiError::unauthorized(),
+            UserErrors::EmailNotVerified => ApiError::new(
+                ErrorCode::Forbidden,
+                "Email address has not been verified, follow the link we emailed you",
+            ),
+            UserErrors::InvalidVerificationToken => {
+                ApiError::bad_request("Verification link is invalid or has expired")
+            }
+            UserErrors::LockedOut(_) => ApiError::new(
+                ErrorCode::TooManyRequests,
+                "Too many failed logins, try again later",
+            ),
+            UserErrors::Validation(field_errors) => field_errors.into_iter().fold(
+                ApiError::validation("Request is invalid"),
+                |error, field_error| error.with_field_error(field_error.field, field_error.message),
+            ),
+            UserErrors::Unavailable(_) => {
+                ApiError::unavailable("Service is temporarily unavailable, try again later")
+            }
+            UserErrors::UnknownFailure => ApiError::internal(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, FromRow)]
 struct User {
     username: String,
     email_address: String,
     password_hash: String,
+    roles: Vec<String>,
+    scopes: Vec<String>,
+    email_verified: bool,
 }
 
 impl User {
@@ -33,6 +95,9 @@ impl User {
             username,
             email_address,
             password_hash,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            email_verified: false,
         }
     }
 
@@ -41,110 +106,205 @@ impl User {
     }
 }
 
-#[derive(Serialize)]
-pub struct UserDTO {
-    username: String,
-    email_address: String,
-}
-
+// Missing fields are left empty, so they are reported with the other field errors.
 #[derive(Deserialize)]
 pub struct RegisterCommand {
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     email: String,
+    #[serde(default)]
     password: String,
 }
 
 impl RegisterCommand {
+    fn validate(&self, password_policy: &PasswordPolicy) -> std::result::Result<(), UserErrors> {
+        let field_errors = field_errors([
+            (
+                "username",
+                required(&self.username).or_else(|| check_username(&self.username)),
+            ),
+            (
+                "email",
+                required(&self.email).or_else(|| check_email(&self.email)),
+            ),
+            (
+                "password",
+                required(&self.password).or_else(|| password_policy.check(&self.password)),
+            ),
+        ]);
+
+        if field_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(UserErrors::Validation(field_errors))
+        }
+    }
+
+    /// Creates the user and emails them a link to `verification_url` to confirm their address.
+    /// The input is validated first, so a rejected request never pays for hashing the password.
     pub async fn handle(
         &self,
         user_repository: &UserRepository,
+        auth_service: &AuthenticationService,
+        password_policy: &PasswordPolicy,
+        verification_url: Url,
     ) -> std::result::Result<UserDTO, UserErrors> {
+        self.validate(password_policy)?;
+
         let user = User::new(
             self.email.clone(),
             self.username.clone(),
             self.password.clone(),
         );
 
-        tracing::info!(
-            "Attempting to create user with details: {:?} {:?}",
-            &user.username,
-            &user.password_hash
-        );
+        tracing::info!("Attempting to create user {:?}", &user.username);
 
-        match user_repository.get_user(&user.username).await {
-            Ok(existing_user) => match existing_user {
-                Some(_) => {
-                    tracing::info!("User exists");
-                    Err(UserErrors::Exists)
-                }
-                None => match user_repository.add_user(user).await {
-                    Ok(_) => Ok(UserDTO {
-                        username: self.username.clone(),
-                        email_address: self.email.clone(),
-                    }),
-                    Err(e) => {
-                        tracing::info!("Failure");
-                        tracing::error!("{}", e);
-                        Err(UserErrors::UnknownFailure)
-                    }
-                },
-            },
-            Err(_) => Err(UserErrors::UnknownFailure),
+        if user_repository.get_user(&user.username).await?.is_some() {
+            tracing::info!("User exists");
+            return Err(UserErrors::Exists("username"));
         }
+
+        user_repository.add_user(user).await?;
+
+        user_repository
+            .send_verification_email(auth_service, &self.username, &self.email, verification_url)
+            .await;
+
+        Ok(UserDTO {
+            username: self.username.clone(),
+            email_address: self.email.clone(),
+        })
     }
 }
 
 #[derive(Deserialize)]
 pub struct LoginCommand {
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     password: String,
 }
 
-#[derive(Serialize)]
-pub struct LoginResponse {
+impl LoginCommand {
+    fn validate(&self) -> std::result::Result<(), UserErrors> {
+        let field_errors = field_errors([
+            ("username", required(&self.username)),
+            ("password", required(&self.password)),
+        ]);
+
+        if field_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(UserErrors::Validation(field_errors))
+        }
+    }
+
+    pub async fn handle(
+        &self,
+        user_repository: &UserRepository,
+        session_repository: &SessionRepository,
+        auth_service: &AuthenticationService,
+        login_attempts: &LoginAttemptRepository,
+        client_ip: Option<&str>,
+    ) -> std::result::Result<TokenResponse, UserErrors> {
+        self.validate()?;
+
+        let locked_for = login_attempts.locked_for(&self.username, client_ip).await?;
+
+        if let Some(retry_after) = locked_for {
+            return Err(UserErrors::LockedOut(retry_after));
+        }
+
+        let user = user_repository.get_user(&self.username).await?;
+
+        // Unknown users are checked against a placeholder hash, so they take as long to reject
+        // as a wrong password.
+        let password_matches = match &user {
+            Some(user) => user.verify_password(&self.password),
+            None => {
+                let _ = verify(&self.password, UNKNOWN_USER_PASSWORD_HASH);
+                false
+            }
+        };
+
+        let user = match user {
+            Some(user) if password_matches => user,
+            _ => {
+                login_attempts
+                    .record_failure(&self.username, client_ip)
+                    .await;
+                return Err(UserErrors::InvalidCredentials);
+            }
+        };
+
+        login_attempts.record_success(&self.username).await;
+
+        if !user.email_verified {
+            return Err(UserErrors::EmailNotVerified);
+        }
+
+        session_repository
+            .start_session(
+                auth_service,
+                TokenSubject {
+                    username: user.username,
+                    roles: user.roles,
+                    scopes: user.scopes,
+                },
+            )
+            .await
+            .map_err(|e| match e {
+                SessionError::Storage(e) => UserErrors::Unavailable(e),
+                e => {
+                    tracing::error!("{}", e);
+                    UserErrors::UnknownFailure
+                }
+            })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailCommand {
     token: String,
 }
 
-impl LoginCommand {
+impl VerifyEmailCommand {
     pub async fn handle(
         &self,
         user_repository: &UserRepository,
         auth_service: &AuthenticationService,
-    ) -> std::result::Result<LoginResponse, UserErrors> {
-        if let Ok(user) = user_repository.get_user(&self.username).await {
-            return match user {
-                Some(user) => {
-                    if user.verify_password(&self.password) {
-                        return match auth_service.generate_token_for(self.username.clone()) {
-                            Ok(token) => Ok(LoginResponse { token }),
-                            Err(_e) => Err(UserErrors::UnknownFailure),
-                        };
-                    }
-
-                    Err(UserErrors::UnknownFailure)
-                }
-                None => Err(UserErrors::UnknownFailure),
-            };
-        }
+    ) -> std::result::Result<(), UserErrors> {
+        let claims = auth_service
+            .verify_email_verification_token(&self.token)
+            .await
+            .map_err(|e| {
+                tracing::info!("Rejecting verification link: {}", e);
+                UserErrors::InvalidVerificationToken
+            })?;
+
+        user_repository
+            .mark_email_verified(&claims.sub, &claims.email)
+            .await?;
 
-        Err(UserErrors::InvalidPassword)
+        Ok(())
     }
 }
 
 pub struct UserRepository {
-    client: Client,
+    client: Rc<Client>,
     queue: Queue,
 }
 
 impl UserRepository {
-    pub fn new(client: Client, queue: Queue) -> Self {
+    pub fn new(client: Rc<Client>, queue: Queue) -> Self {
         Self { client, queue }
     }
 
-    async fn add_user(&self, user: User) -> Result<()> {
+    async fn add_user(&self, user: User) -> std::result::Result<(), UserErrors> {
         tracing::info!("Creating user");
 
-        let result = &self
+        let result = self
             .client
             .query_typed(
                 "INSERT INTO users (email_address, username, password_hash) VALUES ($1, $2, $3)",
@@ -157,53 +317,245 @@ impl UserRepository {
             .await;
 
         match result {
-            Ok(_) => {
-                let _ = &self
-                    .queue
-                    .send(UserDTO {
-                        username: user.username.clone(),
-                        email_address: user.email_address.clone()
-                    })
-                    .await;
+            Ok(_) => Ok(()),
+            // Usernames are checked first, so this is an email address already in use or a
+            // username taken by a registration racing this one.
+            Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                let field = match e.as_db_error().and_then(|e| e.constraint()) {
+                    Some(constraint) if constraint.contains("email") => "email",
+                    _ => "username",
+                };
+
+                Err(UserErrors::Exists(field))
             }
             Err(e) => {
                 tracing::error!("Failure creating user: {}", e);
 
-                return Ok(());
+                Err(e.into())
             }
         }
+    }
+
+    // A failure here leaves the user registered but unable to log in until they are sent a new
+    // link, so it is logged rather than failing the registration.
+    async fn send_verification_email(
+        &self,
+        auth_service: &AuthenticationService,
+        username: &str,
+        email_address: &str,
+        mut verification_url: Url,
+    ) {
+        let token = match auth_service
+            .generate_email_verification_token(username.to_string(), email_address.to_string())
+        {
+            Ok(token) => token,
+            Err(e) => {
+                tracing::error!("{}", e);
+                return;
+            }
+        };
+
+        verification_url.set_query(None);
+        verification_url
+            .query_pairs_mut()
+            .append_pair("token", &token);
+
+        self.notify(UserNotification {
+            notification_type: UserNotificationTypes::VerifyEmail,
+            username: username.to_string(),
+            email_address: email_address.to_string(),
+            link: Some(verification_url.to_string()),
+        })
+        .await;
+    }
+
+    /// Marks the address as verified if it is still the user's, welcoming them the first time.
+    async fn mark_email_verified(
+        &self,
+        username: &str,
+        email_address: &str,
+    ) -> std::result::Result<(), tokio_postgres::Error> {
+        let updated = self
+            .client
+            .query_typed(
+                "UPDATE users SET email_verified = TRUE
+WHERE username = $1 AND email_address = $2 AND email_verified = FALSE
+RETURNING username",
+                &[(&username, Type::TEXT), (&email_address, Type::TEXT)],
+            )
+            .await?;
+
+        if !updated.is_empty() {
+            self.notify(UserNotification {
+                notification_type: UserNotificationTypes::Welcome,
+                username: username.to_string(),
+                email_address: email_address.to_string(),
+                link: None,
+            })
+            .await;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn email_address_for(
+        &self,
+        username: &str,
+    ) -> std::result::Result<Option<String>, tokio_postgres::Error> {
+        Ok(self
+            .get_user(username)
+            .await?
+            .map(|user| user.email_address))
+    }
+
+    pub(crate) async fn send_password_reset_email(
+        &self,
+        username: &str,
+        email_address: &str,
+        reset_url: String,
+    ) {
+        self.notify(UserNotification {
+            notification_type: UserNotificationTypes::PasswordReset,
+            username: username.to_string(),
+            email_address: email_address.to_string(),
+            link: Some(reset_url),
+        })
+        .await;
+    }
+
+    /// Replaces the user's password. Following a reset link proves they own the address it was
+    /// sent to, so it is marked as verified too.
+    pub(crate) async fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        let password_hash = hash(password, DEFAULT_COST)
+            .map_err(|e| worker::Error::RustError(format!("Failure hashing password: {}", e)))?;
+
+        self.client
+            .query_typed(
+                "UPDATE users SET password_hash = $2, email_verified = TRUE WHERE username = $1",
+                &[(&username, Type::TEXT), (&password_hash, Type::TEXT)],
+            )
+            .await
+            .map_err(|e| worker::Error::RustError(format!("Failure updating password: {}", e)))?;
 
         Ok(())
     }
 
-    async fn get_user(&self, username: &str) -> Result<Option<User>> {
-        let result = &self
+    async fn notify(&self, notification: UserNotification) {
+        if let Err(e) = self.queue.send(&notification).await {
+            tracing::error!("Failure queueing notification: {}", e);
+        }
+    }
+
+    // A failed query is returned as an error rather than as no user, so an outage is not
+    // mistaken for a wrong username.
+    async fn get_user(
+        &self,
+        username: &str,
+    ) -> std::result::Result<Option<User>, tokio_postgres::Error> {
+        let rows = self
             .client
             .query_typed(
-                "SELECT username, email_address, password_hash FROM users WHERE username = $1",
+                "SELECT username, email_address, password_hash, roles, scopes, email_verified
+FROM users
+WHERE username = $1",
                 &[(&username, Type::TEXT)],
             )
-            .await;
+            .await
+            .inspect_err(|e| tracing::error!("Failure getting user: {}", e))?;
 
-        match result {
-            Ok(result) => {
-                if result.len() == 1 {
-                    let row = result[0].clone();
-
-                    return Ok(Some(User {
-                        username: row.get(0),
-                        email_address: row.get(1),
-                        password_hash: row.get(2),
-                    }));
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failure getting user: {}", e);
+        Ok(rows.first().map(|row| User {
+            username: row.get(0),
+            email_address: row.get(1),
+            password_hash: row.get(2),
+            roles: row.get(3),
+            scopes: row.get(4),
+            email_verified: row.get(5),
+        }))
+    }
+}
 
-                return Ok(None);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(username: &str, password: &str) -> LoginCommand {
+        LoginCommand {
+            username: username.to_string(),
+            password: password.to_string(),
         }
+    }
+
+    fn status(e: UserErrors) -> u16 {
+        ApiError::from(e).code().status_code()
+    }
+
+    fn fields(e: UserErrors) -> Vec<String> {
+        ApiError::from(e)
+            .field_errors()
+            .iter()
+            .map(|field_error| field_error.field.clone())
+            .collect()
+    }
+
+    #[test]
+    fn invalid_credentials_are_a_uniform_unauthorized() {
+        let error = ApiError::from(UserErrors::InvalidCredentials);
+
+        assert_eq!(error.code(), ErrorCode::Unauthorized);
+        assert_eq!(error.message(), ApiError::unauthorized().message());
+        assert!(error.field_errors().is_empty());
+    }
+
+    #[test]
+    fn an_unavailable_user_store_is_a_service_unavailable() {
+        assert_eq!(
+            status(UserErrors::Unavailable("connection reset".to_string())),
+            503
+        );
+    }
+
+    #[test]
+    fn the_store_error_is_not_returned_to_the_client() {
+        let error = ApiError::from(UserErrors::Unavailable("password_hash".to_string()));
+
+        assert!(!error.message().contains("password_hash"));
+    }
+
+    #[test]
+    fn validation_errors_are_unprocessable_with_their_fields() {
+        let error = UserErrors::Validation(vec![FieldError {
+            field: "username".to_string(),
+            message: "is required".to_string(),
+        }]);
+
+        assert_eq!(status(UserErrors::Validation(Vec::new())), 422);
+        assert_eq!(fields(error), vec!["username"]);
+    }
+
+    #[test]
+    fn an_existing_user_is_a_conflict_on_the_taken_field() {
+        assert_eq!(status(UserErrors::Exists("username")), 409);
+        assert_eq!(fields(UserErrors::Exists("email")), vec!["email"]);
+    }
+
+    #[test]
+    fn every_other_error_maps_to_its_status() {
+        assert_eq!(status(UserErrors::EmailNotVerified), 403);
+        assert_eq!(status(UserErrors::InvalidVerificationToken), 400);
+        assert_eq!(status(UserErrors::LockedOut(30)), 429);
+        assert_eq!(status(UserErrors::UnknownFailure), 500);
+    }
+
+    #[test]
+    fn login_requires_a_username_and_password() {
+        let missing = |command: LoginCommand| match command.validate() {
+            Err(e) => fields(e),
+            Ok(_) => Vec::new(),
+        };
 
-        Ok(None)
+        assert_eq!(missing(login("", "")), vec!["username", "password"]);
+        assert_eq!(missing(login("  ", "password")), vec!["username"]);
+        assert_eq!(missing(login("user", "")), vec!["password"]);
+        assert!(login("user", "password").validate().is_ok());
     }
 }
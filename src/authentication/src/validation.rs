@@ -0,0 +1,239 @@
+use shared::errors::FieldError;
+use worker::Env;
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+const EMAIL_MAX_LENGTH: usize = 254;
+const EMAIL_LOCAL_PART_MAX_LENGTH: usize = 64;
+// bcrypt ignores everything after the first 72 bytes, so longer passwords are not stronger.
+const PASSWORD_MAX_BYTES: usize = 72;
+const DEFAULT_PASSWORD_MIN_LENGTH: usize = 10;
+
+// Passwords that turn up at the top of every breach, one per line in lower case.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// Which passwords users may choose, checked on registration and on reset. The minimum length
+/// is read from the PASSWORD_MIN_LENGTH var.
+pub struct PasswordPolicy {
+    min_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: DEFAULT_PASSWORD_MIN_LENGTH,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        env.var("PASSWORD_MIN_LENGTH")
+            .ok()
+            .and_then(|value| value.to_string().parse().ok())
+            .map(|min_length| PasswordPolicy { min_length })
+            .unwrap_or_default()
+    }
+
+    /// Why the password is not allowed, if it is not.
+    pub fn check(&self, password: &str) -> Option<String> {
+        if password.chars().count() < self.min_length {
+            return Some(format!("must be at least {} characters", self.min_length));
+        }
+
+        if password.len() > PASSWORD_MAX_BYTES {
+            return Some(format!("must be at most {} bytes", PASSWORD_MAX_BYTES));
+        }
+
+        let lowercase = password.to_lowercase();
+        if COMMON_PASSWORDS.lines().any(|common| common == lowercase) {
+            return Some("is too common, choose one that is harder to guess".to_string());
+        }
+
+        None
+    }
+}
+
+pub fn required(value: &str) -> Option<String> {
+    value.trim().is_empty().then(|| "is required".to_string())
+}
+
+pub fn check_username(username: &str) -> Option<String> {
+    let length = username.chars().count();
+
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Some(format!(
+            "must be between {} and {} characters",
+            USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Some("may only contain letters, numbers, underscores and hyphens".to_string());
+    }
+
+    None
+}
+
+// Only catches addresses that cannot be delivered to, whether one exists is left to the
+// verification email.
+pub fn check_email(email: &str) -> Option<String> {
+    let invalid = Some("is not a valid email address".to_string());
+
+    if email.len() > EMAIL_MAX_LENGTH || email.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return invalid;
+    }
+
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return invalid,
+    };
+
+    let local_is_valid = !local.is_empty()
+        && local.len() <= EMAIL_LOCAL_PART_MAX_LENGTH
+        && !local.contains('@')
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..");
+
+    let domain_is_valid = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if local_is_valid && domain_is_valid {
+        None
+    } else {
+        invalid
+    }
+}
+
+/// Collects the checks that failed into per-field errors.
+pub fn field_errors<const N: usize>(checks: [(&str, Option<String>); N]) -> Vec<FieldError> {
+    checks
+        .into_iter()
+        .filter_map(|(field, message)| {
+            message.map(|message| FieldError {
+                field: field.to_string(),
+                message,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_must_meet_the_minimum_length() {
+        let policy = PasswordPolicy::default();
+
+        assert!(policy.check("k7#Qm2x!p").is_some());
+        assert_eq!(policy.check("k7#Qm2x!pZ"), None);
+        assert_eq!(PasswordPolicy { min_length: 4 }.check("k7#Q"), None);
+    }
+
+    // Counted in characters, so a password of multi-byte characters is not cut short.
+    #[test]
+    fn password_length_is_counted_in_characters() {
+        let policy = PasswordPolicy::default();
+
+        assert!(policy.check("ééééééééé").is_some());
+        assert_eq!(policy.check("éééééééééé"), None);
+    }
+
+    #[test]
+    fn passwords_past_what_bcrypt_reads_are_rejected() {
+        let policy = PasswordPolicy::default();
+
+        assert_eq!(policy.check(&"k".repeat(PASSWORD_MAX_BYTES)), None);
+        assert!(policy.check(&"k".repeat(PASSWORD_MAX_BYTES + 1)).is_some());
+        assert!(policy
+            .check(&"é".repeat(PASSWORD_MAX_BYTES / 2 + 1))
+            .is_some());
+    }
+
+    #[test]
+    fn common_passwords_are_rejected_in_any_case() {
+        let policy = PasswordPolicy::default();
+
+        assert!(policy.check("password123").is_some());
+        assert!(policy.check("PassWord123").is_some());
+    }
+
+    #[test]
+    fn usernames_are_checked_for_length_and_characters() {
+        assert_eq!(check_username("abc"), None);
+        assert_eq!(check_username("user_name-42"), None);
+        assert_eq!(check_username(&"a".repeat(USERNAME_MAX_LENGTH)), None);
+
+        assert!(check_username("ab").is_some());
+        assert!(check_username(&"a".repeat(USERNAME_MAX_LENGTH + 1)).is_some());
+        assert!(check_username("a b").is_some());
+        assert!(check_username("user@example").is_some());
+        assert!(check_username("ünïcode").is_some());
+    }
+
+    #[test]
+    fn deliverable_email_addresses_are_accepted() {
+        for email in [
+            "user@example.com",
+            "first.last+chat@mail.example.co.uk",
+            "user@sub-domain.example.com",
+        ] {
+            assert_eq!(check_email(email), None, "{} was rejected", email);
+        }
+    }
+
+    #[test]
+    fn undeliverable_email_addresses_are_rejected() {
+        let long_local_part = format!(
+            "{}@example.com",
+            "a".repeat(EMAIL_LOCAL_PART_MAX_LENGTH + 1)
+        );
+        let long_label = format!("user@{}.com", "a".repeat(64));
+
+        for email in [
+            "",
+            "not-an-email",
+            "@example.com",
+            "user@",
+            "user@localhost",
+            "user@@example.com",
+            "user name@example.com",
+            ".user@example.com",
+            "user.@example.com",
+            "us..er@example.com",
+            "user@-example.com",
+            "user@example-.com",
+            "user@example..com",
+            "user@exa_mple.com",
+            long_local_part.as_str(),
+            long_label.as_str(),
+        ] {
+            assert!(check_email(email).is_some(), "{} was accepted", email);
+        }
+    }
+
+    #[test]
+    fn only_failed_checks_become_field_errors() {
+        let errors = field_errors([
+            ("username", required("")),
+            ("email", None),
+            ("password", required("password")),
+        ]);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "username");
+        assert_eq!(errors[0].message, "is required");
+    }
+}
@@ -0,0 +1,10 @@
+CREATE TABLE chat_webhooks (
+    id TEXT PRIMARY KEY,
+    chat_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_chat_webhooks_chat_id ON chat_webhooks(chat_id);
@@ -0,0 +1,10 @@
+CREATE TABLE chat_bot_tokens (
+    id TEXT PRIMARY KEY,
+    chat_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_by TEXT NOT NULL,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_chat_bot_tokens_chat_id ON chat_bot_tokens(chat_id);
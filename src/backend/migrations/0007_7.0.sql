@@ -0,0 +1,15 @@
+ALTER TABLE chats ADD COLUMN created_at TEXT;
+
+ALTER TABLE chats ADD COLUMN last_activity_at TEXT;
+
+UPDATE chats SET created_at = CURRENT_TIMESTAMP WHERE created_at IS NULL;
+
+UPDATE chats SET last_activity_at = created_at WHERE last_activity_at IS NULL;
+
+CREATE INDEX idx_chats_created_at ON chats(created_at, id);
+
+CREATE INDEX idx_chats_last_activity_at ON chats(last_activity_at, id);
+
+CREATE INDEX idx_chats_name ON chats(name, id);
+
+CREATE INDEX idx_chats_created_by_created_at ON chats(created_by, created_at, id);
@@ -0,0 +1,7 @@
+CREATE TABLE chat_tags (
+    chat_id TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (chat_id, tag)
+);
+
+CREATE INDEX idx_chat_tags_tag ON chat_tags(tag, chat_id);
@@ -0,0 +1,3 @@
+ALTER TABLE chats ADD COLUMN private INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_chats_private_created_at ON chats(private, created_at, id);
@@ -0,0 +1,8 @@
+CREATE TABLE connect_tickets (
+    ticket_hash TEXT PRIMARY KEY,
+    chat_id TEXT NOT NULL,
+    claims TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_connect_tickets_expires_at ON connect_tickets(expires_at);
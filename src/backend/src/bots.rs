@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use worker::D1Database;
+
+use crate::chats::ChatError;
+
+#[derive(Deserialize)]
+pub struct CreateBotTokenCommand {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostMessageCommand {
+    pub contents: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BotToken {
+    pub id: String,
+    pub chat_id: String,
+    pub name: String,
+    pub token_hash: String,
+    pub created_by: String,
+}
+
+// Only the hash of a bot token is stored, so the plain token is returned once on creation.
+#[derive(Serialize)]
+pub struct CreatedBotToken {
+    pub id: String,
+    pub chat_id: String,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BotTokenDTO {
+    pub id: String,
+    pub chat_id: String,
+    pub name: String,
+}
+
+impl BotTokenDTO {
+    fn from(bot_token: &BotToken) -> Self {
+        BotTokenDTO {
+            id: bot_token.id.clone(),
+            chat_id: bot_token.chat_id.clone(),
+            name: bot_token.name.clone(),
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub struct BotTokenRepository {
+    database: D1Database,
+}
+
+impl BotTokenRepository {
+    pub fn new(database: D1Database) -> Self {
+        BotTokenRepository { database }
+    }
+
+    pub async fn list_bot_tokens(&self, chat_id: &str) -> Result<Vec<BotTokenDTO>, ChatError> {
+        let bot_tokens = self
+            .database
+            .prepare(
+                "SELECT id, chat_id, name, token_hash, created_by
+FROM chat_bot_tokens
+WHERE chat_id = ?1",
+            )
+            .bind(&[JsValue::from(chat_id)])?
+            .all()
+            .await?
+            .results::<BotToken>()?;
+
+        Ok(bot_tokens.iter().map(BotTokenDTO::from).collect())
+    }
+
+    pub async fn create_bot_token(
+        &self,
+        chat_id: String,
+        name: String,
+        created_by: String,
+    ) -> Result<CreatedBotToken, ChatError> {
+        let id = Uuid::new_v4().to_string();
+        let token = format!("bot_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        self.database
+            .prepare(
+                "INSERT INTO chat_bot_tokens
+            (id, chat_id, name, token_hash, created_by)
+            VALUES
+            (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(&[
+                JsValue::from(&id),
+                JsValue::from(&chat_id),
+                JsValue::from(&name),
+                JsValue::from(hash_token(&token)),
+                JsValue::from(created_by),
+            ])?
+            .run()
+            .await?;
+
+        Ok(CreatedBotToken {
+            id,
+            chat_id,
+            name,
+            token,
+        })
+    }
+
+    pub async fn delete_bot_token(&self, chat_id: &str, token_id: &str) -> Result<(), ChatError> {
+        self.database
+            .prepare(
+                "DELETE FROM chat_bot_tokens
+WHERE chat_id = ?1 AND id = ?2",
+            )
+            .bind(&[JsValue::from(chat_id), JsValue::from(token_id)])?
+            .run()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the bot the token belongs to, if it is valid for the given chat.
+    pub async fn verify_bot_token(&self, chat_id: &str, token: &str) -> Option<BotToken> {
+        let db_token = &self
+            .database
+            .prepare(
+                "SELECT id, chat_id, name, token_hash, created_by
+FROM chat_bot_tokens
+WHERE chat_id = ?1 AND token_hash = ?2",
+            )
+            .bind(&[JsValue::from(chat_id), JsValue::from(hash_token(token))])
+            .ok()?
+            .first::<BotToken>(None)
+            .await;
+
+        match db_token {
+            Ok(bot_token) => bot_token.clone(),
+            Err(e) => {
+                warn!("Failure verifying bot token: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -1,28 +1,106 @@
-use std::time::Duration;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
 
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use shared::{
+    auth::{AuthenticationService, ADMIN_ROLE, MODERATOR_ROLE},
+    events::{ChatEvent, ChatEventTypes},
+    revocation::RevocationStore,
+};
 use tracing::{info, warn};
 use worker::{
-    durable_object, Env, Request, Response, Result, State, WebSocket, WebSocketIncomingMessage,
-    WebSocketPair,
+    durable_object, Date, Env, Headers, Method, Request, Response, Result, State, WebSocket,
+    WebSocketIncomingMessage, WebSocketPair,
 };
 
 use crate::{
     chats::ChatRepository,
+    commands::{help_text, ChatCommand, CommandError},
+    encoding::{EncodedFrame, FrameEncoding},
     messaging::{
-        ChatroomEnded, ConnectionUpdate, IncomingMessageType, Message, MessageHistory,
-        MessageTypes, MessageWrapper,
+        add_poll, AuthRefreshed, ChatroomEnded, ClosePoll, CommandReply, ConnectionUpdate,
+        CreatePoll, IncomingMessage, IncomingMessageType, Message, MessageHistory, MessageTypes,
+        MessageWrapper, Poll, PollError, PostedMessage, RefreshAuth, RoomSettings, TopicUpdated,
+        Vote,
     },
+    webhooks::ChatEventPublisher,
 };
 
+// Close code sent to a socket that is removed from the room with /kick.
+const KICKED_CLOSE_CODE: u16 = 4001;
+// Close code sent to a socket whose token expired without being refreshed.
+const TOKEN_EXPIRED_CLOSE_CODE: u16 = 4002;
+// Busy rooms only need to bump `last_activity_at` in D1 every so often for sorting by activity.
+const ACTIVITY_RECORD_INTERVAL_IN_MILLIS: u64 = 60_000;
+// Close code sent to every socket when the chat is deleted or expires.
+const CHATROOM_ENDED_CLOSE_CODE: u16 = 4003;
+// Close code sent to a socket whose token was revoked by logging out.
+const TOKEN_REVOKED_CLOSE_CODE: u16 = 4004;
+// Revocations are not known in advance, so while anyone is connected the alarm also fires this
+// often to look for them.
+const REVOCATION_CHECK_INTERVAL_IN_MILLIS: u64 = 60_000;
+
 #[derive(Deserialize, Serialize)]
 struct QueryStringParameters {
     user_id: String,
+    #[serde(default)]
+    encoding: Option<String>,
+    #[serde(default)]
+    token_exp: Option<u64>,
+    #[serde(default)]
+    token_jti: Option<String>,
+    #[serde(default)]
+    token_iat: Option<u64>,
+    // Comma separated roles from the token the connection was authenticated with.
+    #[serde(default)]
+    roles: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 struct WebsocketConnectionAttachments {
     user_id: String,
+    #[serde(default)]
+    encoding: FrameEncoding,
+    // The `exp` of the token the connection was authenticated with, in seconds since the epoch.
+    #[serde(default)]
+    token_expires_at: Option<u64>,
+    #[serde(default)]
+    token: Option<ConnectionToken>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+// What is needed to look the token a connection was authenticated with up in the
+// RevocationStore. Bot tokens have no id and are never revoked this way.
+#[derive(Deserialize, Serialize, Clone)]
+struct ConnectionToken {
+    jti: String,
+    issued_at: u64,
+}
+
+impl ConnectionToken {
+    fn new(jti: Option<String>, issued_at: Option<u64>) -> Option<Self> {
+        match (jti, issued_at) {
+            (Some(jti), Some(issued_at)) if !jti.is_empty() => {
+                Some(ConnectionToken { jti, issued_at })
+            }
+            _ => None,
+        }
+    }
+}
+
+// A client receiving room events over Server-Sent Events. Unlike WebSockets these are held in
+// memory, so the Chatroom cannot hibernate while one is connected.
+struct SseSubscriber {
+    user_id: String,
+    token_expires_at: Option<u64>,
+    token: Option<ConnectionToken>,
+    sender: UnboundedSender<String>,
 }
 
 #[durable_object]
@@ -30,8 +108,14 @@ pub struct Chatroom {
     state: State,
     _env: Env,
     chat_repository: ChatRepository,
+    event_publisher: ChatEventPublisher,
+    auth_service: AuthenticationService,
+    revocation_store: RevocationStore,
+    sse_subscribers: Vec<SseSubscriber>,
+    closed_sse_users: Vec<String>,
     messages_storage_key: String,
     chat_expiry_in_seconds: u64,
+    activity_recorded_at: u64,
 }
 
 #[durable_object]
@@ -39,13 +123,22 @@ impl DurableObject for Chatroom {
     fn new(state: State, env: Env) -> Self {
         let database = env.d1("CHAT_METADATA").unwrap();
         let cache = env.kv("CHAT_CACHE").unwrap();
+        let event_publisher = ChatEventPublisher::new(env.queue("CHAT_EVENTS").ok());
+        let authentication_binding = env.service("AUTH").unwrap();
+        let revoked_tokens = env.kv("REVOKED_TOKENS").unwrap();
 
         Self {
             state,
             _env: env,
             chat_repository: ChatRepository::new(database, cache),
+            event_publisher,
+            auth_service: AuthenticationService::verifier(authentication_binding),
+            revocation_store: RevocationStore::new(revoked_tokens),
+            sse_subscribers: Vec::new(),
+            closed_sse_users: Vec::new(),
             messages_storage_key: "messages".to_string(),
             chat_expiry_in_seconds: 300,
+            activity_recorded_at: 0,
         }
     }
 
@@ -57,19 +150,43 @@ impl DurableObject for Chatroom {
         let paths = paths.collect::<Box<[_]>>();
 
         let _ = &self.update_chat_expiry().await;
+        self.release_closed_sse_subscribers().await;
 
         match paths[1] {
             "connect" => self.handle_connect(req, paths).await,
+            "events" => self.handle_subscribe(req, paths).await,
+            "messages" => self.handle_posted_message(req, paths).await,
+            "room" => match req.method() {
+                Method::Patch => self.handle_update_settings(req).await,
+                Method::Delete => self.handle_delete(paths).await,
+                _ => Response::error("Method Not Allowed", 405),
+            },
             _ => Ok(Response::builder()
                 .with_status(404)
                 .body(worker::ResponseBody::Empty)),
         }
     }
 
+    // The alarm is set for whichever comes first of the chat expiring and a connection's token
+    // expiring, so it only ends the chat once the chat's own expiry has passed.
     async fn alarm(&mut self) -> Result<Response> {
         info!("Alarm triggered");
 
-        let chat_id = self.state.storage().get("chat_id").await.map_err(|e| {
+        let chat_expires_at = self
+            .state
+            .storage()
+            .get::<u64>("chat_expires_at")
+            .await
+            .unwrap_or(0);
+
+        if chat_expires_at > Date::now().as_millis() {
+            self.close_unauthorized_connections().await;
+            self.schedule_alarm().await;
+
+            return Response::ok("ALARMED");
+        }
+
+        let chat_id: String = self.state.storage().get("chat_id").await.map_err(|e| {
             warn!("{}", e);
             worker::Error::RustError("Failure retrieving chat id".to_string())
         })?;
@@ -78,47 +195,74 @@ impl DurableObject for Chatroom {
 
         let _ = self.chat_repository.delete_chat(&chat_id).await;
 
-        let web_socket_conns = self.state.get_websockets();
-
-        let message_wrapper =
-            MessageWrapper::new(MessageTypes::ChatroomEnded, ChatroomEnded::new(chat_id));
-
-        for conn in web_socket_conns {
-            let _ = conn.send(&message_wrapper);
-        }
+        self.end_chat(chat_id).await?;
 
         Response::ok("ALARMED")
     }
 
     async fn websocket_message(
         &mut self,
-        _ws: WebSocket,
+        ws: WebSocket,
         message: WebSocketIncomingMessage,
     ) -> Result<()> {
         let _ = self.update_chat_expiry().await;
+        self.release_closed_sse_subscribers().await;
 
-        match message {
+        // Text frames are always JSON, binary frames use the encoding negotiated on connect.
+        let (frame, encoding) = match message {
             WebSocketIncomingMessage::String(str_data) => {
-                let incoming_message: IncomingMessageType =
-                    serde_json::from_str(&str_data).unwrap();
+                (str_data.into_bytes(), FrameEncoding::Json)
+            }
+            WebSocketIncomingMessage::Binary(binary_data) => {
+                (binary_data, connection_encoding(&ws))
+            }
+        };
 
-                if incoming_message.message_type.as_str() == "NewMessage" {
-                    let wrapper: MessageWrapper<Message> = serde_json::from_str(&str_data).unwrap();
+        let incoming_message: IncomingMessageType = encoding.decode(&frame)?;
 
-                    let _ = &self.new_message(wrapper.message).await;
-                }
+        // A refresh is still accepted from a socket whose token has just expired, anything else
+        // closes it.
+        if incoming_message.message_type.as_str() == "RefreshAuth" {
+            let wrapper: MessageWrapper<RefreshAuth> = encoding.decode(&frame)?;
+
+            return self.refresh_auth(&ws, wrapper.message).await;
+        }
+
+        if token_has_expired(connection_token_expiry(&ws), Date::now().as_millis() / 1000) {
+            close_expired_connection(&ws);
+            return Ok(());
+        }
+
+        match incoming_message.message_type.as_str() {
+            "NewMessage" => {
+                let wrapper: MessageWrapper<IncomingMessage> = encoding.decode(&frame)?;
+
+                let _ = &self.handle_incoming_message(&ws, wrapper.message).await;
             }
-            WebSocketIncomingMessage::Binary(binary_data) => {
-                let incoming_message: IncomingMessageType =
-                    serde_json::from_slice(&binary_data).unwrap();
+            "CreatePoll" => {
+                let wrapper: MessageWrapper<CreatePoll> = encoding.decode(&frame)?;
 
-                if incoming_message.message_type.as_str() == "NewMessage" {
-                    let wrapper: MessageWrapper<Message> =
-                        serde_json::from_slice(&binary_data).unwrap();
+                let user_id = connection_user_id(&ws)?;
+                let _ = &self.create_poll(&ws, user_id, wrapper.message).await;
+            }
+            "Vote" => {
+                let wrapper: MessageWrapper<Vote> = encoding.decode(&frame)?;
+
+                let user_id = connection_user_id(&ws)?;
+                let vote = wrapper.message;
+                let _ = &self
+                    .update_poll(&ws, &vote.poll_id, |poll| poll.vote(user_id, vote.option))
+                    .await;
+            }
+            "ClosePoll" => {
+                let wrapper: MessageWrapper<ClosePoll> = encoding.decode(&frame)?;
 
-                    let _ = &self.new_message(wrapper.message).await;
-                }
+                let user_id = connection_user_id(&ws)?;
+                let _ = &self
+                    .update_poll(&ws, &wrapper.message.poll_id, |poll| poll.close(&user_id))
+                    .await;
             }
+            _ => {}
         }
 
         Ok(())
@@ -133,17 +277,7 @@ impl DurableObject for Chatroom {
     ) -> Result<()> {
         info!("Client disconnected");
 
-        let connection_attachments = ws
-            .deserialize_attachment::<WebsocketConnectionAttachments>()
-            .map_err(|e| {
-                warn!("{}", e);
-                worker::Error::RustError("Failure parsing attachments".to_string())
-            })?;
-
-        let user_id = match connection_attachments {
-            Some(attachments) => attachments.user_id,
-            None => "".to_string(),
-        };
+        let user_id = connection_user_id(&ws)?;
 
         let _ = &self
             .update_connection_count(UpdateConnectionCountTypes::Decrease, user_id)
@@ -156,13 +290,183 @@ impl DurableObject for Chatroom {
 }
 
 impl Chatroom {
-    async fn update_chat_expiry(&mut self) -> () {
-        // Chats are only active for a rolling 5 minute window.
+    async fn update_chat_expiry(&mut self) {
+        // Chats are only active for a rolling window, 5 minutes unless the owner changed it.
+        let chat_expiry_in_seconds = self
+            .state
+            .storage()
+            .get::<u64>("chat_expiry_in_seconds")
+            .await
+            .unwrap_or(self.chat_expiry_in_seconds);
+
+        let chat_expires_at = Date::now().as_millis() + chat_expiry_in_seconds * 1000;
         let _ = self
             .state
             .storage()
-            .set_alarm(Duration::from_secs(self.chat_expiry_in_seconds.clone()))
+            .put("chat_expires_at", chat_expires_at)
+            .await;
+
+        self.schedule_alarm().await;
+    }
+
+    // Sets the alarm for the chat's expiry, or sooner if a connection has to be closed first.
+    async fn schedule_alarm(&mut self) {
+        let chat_expires_at = match self.state.storage().get::<u64>("chat_expires_at").await {
+            Ok(chat_expires_at) => chat_expires_at,
+            Err(e) => {
+                warn!("Failure reading chat expiry: {}", e);
+                return;
+            }
+        };
+
+        let token_expiries = self
+            .state
+            .get_websockets()
+            .iter()
+            .filter_map(connection_attachments)
+            .map(|attachments| attachments.token_expires_at)
+            .chain(
+                self.sse_subscribers
+                    .iter()
+                    .map(|subscriber| subscriber.token_expires_at),
+            )
+            .collect::<Vec<_>>();
+
+        let now = Date::now().as_millis();
+        let alarm_at = next_alarm_at(chat_expires_at, &token_expiries, now);
+
+        let _ = self
+            .state
+            .storage()
+            .set_alarm(Duration::from_millis(alarm_at.saturating_sub(now)))
+            .await;
+    }
+
+    // Closes every connection whose token has expired or been revoked since it connected.
+    async fn close_unauthorized_connections(&mut self) {
+        let now = Date::now().as_millis() / 1000;
+
+        for conn in self.state.get_websockets() {
+            let attachments = match connection_attachments(&conn) {
+                Some(attachments) => attachments,
+                None => continue,
+            };
+
+            if token_has_expired(attachments.token_expires_at, now) {
+                close_expired_connection(&conn);
+            } else if self
+                .token_is_revoked(&attachments.user_id, attachments.token.as_ref())
+                .await
+            {
+                close_revoked_connection(&conn);
+            }
+        }
+
+        let subscribers = std::mem::take(&mut self.sse_subscribers);
+
+        for subscriber in subscribers {
+            if token_has_expired(subscriber.token_expires_at, now)
+                || self
+                    .token_is_revoked(&subscriber.user_id, subscriber.token.as_ref())
+                    .await
+            {
+                // Dropping the sender ends the stream.
+                self.closed_sse_users.push(subscriber.user_id);
+            } else {
+                self.sse_subscribers.push(subscriber);
+            }
+        }
+
+        self.release_closed_sse_subscribers().await;
+    }
+
+    async fn token_is_revoked(&self, user_id: &str, token: Option<&ConnectionToken>) -> bool {
+        match token {
+            Some(token) => {
+                self.revocation_store
+                    .is_token_revoked(user_id, &token.jti, token.issued_at)
+                    .await
+            }
+            None => false,
+        }
+    }
+
+    async fn handle_update_settings(&mut self, mut req: Request) -> Result<Response> {
+        let settings: RoomSettings = req.json().await.map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure parsing room settings".to_string())
+        })?;
+
+        if let Some(expiry_in_seconds) = settings.expiry_in_seconds {
+            self.state
+                .storage()
+                .put("chat_expiry_in_seconds", expiry_in_seconds)
+                .await
+                .map_err(|e| {
+                    warn!("{}", e);
+                    worker::Error::RustError("Failure updating expiry in DO storage".to_string())
+                })?;
+
+            self.update_chat_expiry().await;
+        }
+
+        if let Some(topic) = settings.topic {
+            self.state
+                .storage()
+                .put("topic", &topic)
+                .await
+                .map_err(|e| {
+                    warn!("{}", e);
+                    worker::Error::RustError("Failure updating topic in DO storage".to_string())
+                })?;
+
+            self.broadcast(&MessageWrapper::new(
+                MessageTypes::TopicUpdated,
+                TopicUpdated::new(topic, settings.updated_by),
+            ));
+        }
+
+        Ok(Response::empty()?.with_status(204))
+    }
+
+    // The chat has already been removed from D1 by the worker.
+    async fn handle_delete(&mut self, paths: Box<[&str]>) -> Result<Response> {
+        self.end_chat(paths[2].to_string()).await?;
+
+        Ok(Response::empty()?.with_status(204))
+    }
+
+    // Tells everyone in the room it has ended, disconnects them and forgets the room entirely.
+    async fn end_chat(&mut self, chat_id: String) -> Result<()> {
+        let chatroom_ended = ChatroomEnded::new(chat_id.clone());
+
+        self.broadcast(&MessageWrapper::new(
+            MessageTypes::ChatroomEnded,
+            chatroom_ended.clone(),
+        ));
+
+        self.event_publisher
+            .publish(ChatEvent::new(
+                ChatEventTypes::ChatEnded,
+                chat_id,
+                &chatroom_ended,
+            ))
             .await;
+
+        for conn in self.state.get_websockets() {
+            let _ = conn.close(Some(CHATROOM_ENDED_CLOSE_CODE), Some("Chatroom ended"));
+        }
+
+        // Dropping the senders ends each SSE stream.
+        self.sse_subscribers.clear();
+        self.closed_sse_users.clear();
+
+        let _ = self.state.storage().delete_alarm().await;
+
+        self.state.storage().delete_all().await.map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure clearing DO storage".to_string())
+        })
     }
 
     async fn handle_connect(&mut self, req: Request, paths: Box<[&str]>) -> Result<Response> {
@@ -185,12 +489,32 @@ impl Chatroom {
 
         info!("Connecting websocket for {}", user_id_query_param.user_id);
 
+        let subprotocols = req.headers().get("Sec-WebSocket-Protocol")?;
+        let (encoding, subprotocol) = FrameEncoding::negotiate(
+            user_id_query_param.encoding.as_deref(),
+            subprotocols.as_deref(),
+        );
+
         let WebSocketPair { client, server } = WebSocketPair::new()?;
         self.state.accept_web_socket(&server);
 
         server
             .serialize_attachment(&WebsocketConnectionAttachments {
                 user_id: user_id_query_param.user_id.clone(),
+                encoding,
+                token_expires_at: user_id_query_param.token_exp,
+                token: ConnectionToken::new(
+                    user_id_query_param.token_jti.clone(),
+                    user_id_query_param.token_iat,
+                ),
+                roles: user_id_query_param
+                    .roles
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|role| !role.is_empty())
+                    .map(|role| role.to_string())
+                    .collect(),
             })
             .map_err(|e| {
                 warn!("{}", e);
@@ -204,13 +528,109 @@ impl Chatroom {
             worker::Error::RustError("Failure loading messages from datastore".to_string())
         })?;
 
-        server
-            .send(&MessageWrapper::new(
+        let topic = self.state.storage().get::<String>("topic").await.ok();
+        let polls = self
+            .load_polls()
+            .await
+            .iter()
+            .map(Poll::to_update)
+            .collect();
+
+        encoding
+            .encode(&MessageWrapper::new(
                 MessageTypes::MessageHistory,
-                MessageHistory::new(messages),
-            ))
+                MessageHistory::new(messages, topic, polls),
+            ))?
+            .send(&server)
             .unwrap_or(());
 
+        self.event_publisher
+            .publish(ChatEvent::new(
+                ChatEventTypes::UserJoined,
+                chat_id.to_string(),
+                &json!({ "user_id": user_id_query_param.user_id }),
+            ))
+            .await;
+
+        let _ = &self
+            .update_connection_count(
+                UpdateConnectionCountTypes::Increase,
+                user_id_query_param.user_id,
+            )
+            .await?;
+
+        self.schedule_alarm().await;
+
+        let mut response = Response::from_websocket(client)?;
+
+        if let Some(subprotocol) = subprotocol {
+            response
+                .headers_mut()
+                .set("Sec-WebSocket-Protocol", subprotocol)?;
+        }
+
+        Ok(response)
+    }
+
+    async fn handle_subscribe(&mut self, req: Request, paths: Box<[&str]>) -> Result<Response> {
+        let chat_id = paths[2];
+
+        self.state
+            .storage()
+            .put("chat_id", chat_id)
+            .await
+            .map_err(|e| {
+                warn!("{}", e);
+                worker::Error::RustError("Failure updating chat_id against DO storage".to_string())
+            })?;
+
+        let user_id_query_param = req.query::<QueryStringParameters>().map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure parsing query parameters".to_string())
+        })?;
+
+        info!("Subscribing SSE stream for {}", user_id_query_param.user_id);
+
+        let (sender, receiver) = unbounded::<String>();
+
+        let messages = self.load_messages().await.map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure loading messages from datastore".to_string())
+        })?;
+
+        let topic = self.state.storage().get::<String>("topic").await.ok();
+        let polls = self
+            .load_polls()
+            .await
+            .iter()
+            .map(Poll::to_update)
+            .collect();
+
+        let _ = sender.unbounded_send(sse_event(&MessageWrapper::new(
+            MessageTypes::MessageHistory,
+            MessageHistory::new(messages, topic, polls),
+        )));
+
+        self.sse_subscribers.push(SseSubscriber {
+            user_id: user_id_query_param.user_id.clone(),
+            token_expires_at: user_id_query_param.token_exp,
+            token: ConnectionToken::new(
+                user_id_query_param.token_jti.clone(),
+                user_id_query_param.token_iat,
+            ),
+            sender,
+        });
+
+        self.schedule_alarm().await;
+
+        self.event_publisher
+            .publish(ChatEvent::new(
+                ChatEventTypes::UserJoined,
+                chat_id.to_string(),
+                &json!({ "user_id": user_id_query_param.user_id }),
+            ))
+            .await;
+
         let _ = &self
             .update_connection_count(
                 UpdateConnectionCountTypes::Increase,
@@ -218,7 +638,105 @@ impl Chatroom {
             )
             .await?;
 
-        Response::from_websocket(client)
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "text/event-stream")?;
+        headers.set("Cache-Control", "no-cache")?;
+
+        Ok(Response::from_stream(
+            receiver.map(|event| Ok::<Vec<u8>, worker::Error>(event.into_bytes())),
+        )?
+        .with_headers(headers))
+    }
+
+    // SSE disconnects are only noticed when a broadcast to the stream fails, so the connection
+    // count is updated on the next request the Chatroom handles.
+    async fn release_closed_sse_subscribers(&mut self) {
+        let closed_users = std::mem::take(&mut self.closed_sse_users);
+
+        for user_id in closed_users {
+            info!("SSE client disconnected");
+            let _ = self
+                .update_connection_count(UpdateConnectionCountTypes::Decrease, user_id)
+                .await;
+        }
+    }
+
+    async fn refresh_auth(&mut self, ws: &WebSocket, refresh: RefreshAuth) -> Result<()> {
+        let attachments = match connection_attachments(ws) {
+            Some(attachments) => attachments,
+            None => return Ok(()),
+        };
+
+        let claims = match self.auth_service.verify_jwt_token(&refresh.token).await {
+            Ok(claims)
+                if claims.sub == attachments.user_id
+                    && !self.revocation_store.is_revoked(&claims).await =>
+            {
+                claims
+            }
+            _ => {
+                return send_reply(
+                    ws,
+                    CommandReply::error("Refreshed token is not valid for this user".to_string()),
+                )
+            }
+        };
+
+        let token_expires_at = claims.exp as u64;
+
+        ws.serialize_attachment(&WebsocketConnectionAttachments {
+            token_expires_at: Some(token_expires_at),
+            token: ConnectionToken::new(Some(claims.jti), Some(claims.iat as u64)),
+            roles: claims.roles,
+            ..attachments
+        })
+        .map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure updating websocket attachments".to_string())
+        })?;
+
+        connection_encoding(ws)
+            .encode(&MessageWrapper::new(
+                MessageTypes::AuthRefreshed,
+                AuthRefreshed::new(token_expires_at),
+            ))?
+            .send(ws)
+    }
+
+    async fn handle_posted_message(
+        &mut self,
+        mut req: Request,
+        paths: Box<[&str]>,
+    ) -> Result<Response> {
+        let chat_id = paths[2];
+
+        self.state
+            .storage()
+            .put("chat_id", chat_id)
+            .await
+            .map_err(|e| {
+                warn!("{}", e);
+                worker::Error::RustError("Failure updating chat_id against DO storage".to_string())
+            })?;
+
+        let posted_message: PostedMessage = req.json().await.map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure parsing posted message".to_string())
+        })?;
+
+        if self.is_muted(&posted_message.user).await {
+            return Response::error("Sender is muted in this chatroom", 403);
+        }
+
+        let message = if posted_message.bot {
+            Message::bot(posted_message.user, posted_message.contents)
+        } else {
+            Message::text(posted_message.user, posted_message.contents)
+        };
+
+        self.new_message(message.clone()).await?;
+
+        Response::from_json(&message)
     }
 
     async fn new_message(&mut self, message: Message) -> Result<Response> {
@@ -240,27 +758,345 @@ impl Chatroom {
                 worker::Error::RustError("Failuring updating messages in DO storage".to_string())
             })?;
 
-        let web_socket_conns = self.state.get_websockets();
+        self.broadcast(&MessageWrapper::new(
+            MessageTypes::NewMessage,
+            message.clone(),
+        ));
+
+        if let Ok(chat_id) = self.state.storage().get::<String>("chat_id").await {
+            self.record_activity(&chat_id).await;
+
+            self.event_publisher
+                .publish(ChatEvent::new(
+                    ChatEventTypes::MessageCreated,
+                    chat_id,
+                    &message,
+                ))
+                .await;
+        }
 
-        let message_wrapper = MessageWrapper::new(MessageTypes::NewMessage, message);
+        Response::from_json(&messages)
+    }
 
-        for conn in web_socket_conns {
-            let _ = conn.send(&message_wrapper);
+    async fn record_activity(&mut self, chat_id: &str) {
+        let now = Date::now().as_millis();
+
+        if now - self.activity_recorded_at < ACTIVITY_RECORD_INTERVAL_IN_MILLIS {
+            return;
         }
 
-        Response::from_json(&messages)
+        if self.chat_repository.record_activity(chat_id).await.is_ok() {
+            self.activity_recorded_at = now;
+        }
+    }
+
+    async fn handle_incoming_message(
+        &mut self,
+        ws: &WebSocket,
+        message: IncomingMessage,
+    ) -> Result<()> {
+        let user_id = connection_user_id(ws)?;
+
+        match ChatCommand::parse(&message.contents) {
+            Some(Ok(command)) => self.handle_command(ws, user_id, command).await,
+            Some(Err(e)) => send_reply(ws, CommandReply::error(e.to_string())),
+            None => {
+                if self.reject_if_muted(ws, &user_id).await? {
+                    return Ok(());
+                }
+
+                self.new_message(Message::text(user_id, message.contents))
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    async fn handle_command(
+        &mut self,
+        ws: &WebSocket,
+        user_id: String,
+        command: ChatCommand,
+    ) -> Result<()> {
+        let definition = command.definition();
+
+        if definition.moderator_only && !self.is_moderator(ws, &user_id).await {
+            return send_reply(
+                ws,
+                CommandReply::error(CommandError::NotPermitted(definition.name).to_string()),
+            );
+        }
+
+        match command {
+            ChatCommand::Me(action) => {
+                if self.reject_if_muted(ws, &user_id).await? {
+                    return Ok(());
+                }
+
+                self.new_message(Message::action(user_id, action))
+                    .await
+                    .map(|_| ())
+            }
+            ChatCommand::Topic(topic) => {
+                if self.reject_if_muted(ws, &user_id).await? {
+                    return Ok(());
+                }
+
+                self.state
+                    .storage()
+                    .put("topic", &topic)
+                    .await
+                    .map_err(|e| {
+                        warn!("{}", e);
+                        worker::Error::RustError("Failure updating topic in DO storage".to_string())
+                    })?;
+
+                self.broadcast(&MessageWrapper::new(
+                    MessageTypes::TopicUpdated,
+                    TopicUpdated::new(topic, user_id),
+                ));
+
+                Ok(())
+            }
+            ChatCommand::Poll(command) => self.create_poll(ws, user_id, command).await,
+            ChatCommand::Kick(target) => {
+                let mut kicked = 0;
+
+                for conn in self.state.get_websockets() {
+                    if connection_user_id(&conn).unwrap_or_default() == target {
+                        let _ = send_reply(
+                            &conn,
+                            CommandReply::error(format!("You were kicked by {}", user_id)),
+                        );
+                        let _ = conn.close(Some(KICKED_CLOSE_CODE), Some("Kicked by moderator"));
+                        kicked += 1;
+                    }
+                }
+
+                if kicked == 0 {
+                    return send_reply(
+                        ws,
+                        CommandReply::error(format!("{} is not in this chatroom", target)),
+                    );
+                }
+
+                send_reply(ws, CommandReply::info(format!("Kicked {}", target)))
+            }
+            ChatCommand::Mute(target) => {
+                let mut muted_users = self.load_muted_users().await;
+
+                if !muted_users.contains(&target) {
+                    muted_users.push(target.clone());
+                    self.save_muted_users(&muted_users).await?;
+                }
+
+                send_reply(ws, CommandReply::info(format!("Muted {}", target)))
+            }
+            ChatCommand::Unmute(target) => {
+                let mut muted_users = self.load_muted_users().await;
+                muted_users.retain(|x| x != &target);
+                self.save_muted_users(&muted_users).await?;
+
+                send_reply(ws, CommandReply::info(format!("Unmuted {}", target)))
+            }
+            ChatCommand::Help => send_reply(ws, CommandReply::info(help_text())),
+        }
+    }
+
+    async fn create_poll(
+        &mut self,
+        ws: &WebSocket,
+        user_id: String,
+        command: CreatePoll,
+    ) -> Result<()> {
+        if self.reject_if_muted(ws, &user_id).await? {
+            return Ok(());
+        }
+
+        let poll = match Poll::new(command, user_id) {
+            Ok(poll) => poll,
+            Err(e) => return send_reply(ws, CommandReply::error(e.to_string())),
+        };
+
+        let mut polls = self.load_polls().await;
+        if let Err(e) = add_poll(&mut polls, poll.clone()) {
+            return send_reply(ws, CommandReply::error(e.to_string()));
+        }
+        self.save_polls(&polls).await?;
+
+        self.broadcast(&MessageWrapper::new(
+            MessageTypes::PollUpdated,
+            poll.to_update(),
+        ));
+
+        Ok(())
+    }
+
+    async fn update_poll<F>(&mut self, ws: &WebSocket, poll_id: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut Poll) -> std::result::Result<(), PollError>,
+    {
+        let mut polls = self.load_polls().await;
+
+        let result = match polls.iter_mut().find(|poll| poll.id() == poll_id) {
+            Some(poll) => update(poll).map(|_| poll.to_update()),
+            None => Err(PollError::NotFound),
+        };
+
+        match result {
+            Ok(poll_update) => {
+                self.save_polls(&polls).await?;
+
+                self.broadcast(&MessageWrapper::new(MessageTypes::PollUpdated, poll_update));
+
+                Ok(())
+            }
+            Err(e) => send_reply(ws, CommandReply::error(e.to_string())),
+        }
+    }
+
+    async fn load_polls(&self) -> Vec<Poll> {
+        self.state
+            .storage()
+            .get::<Vec<Poll>>("polls")
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn save_polls(&self, polls: &[Poll]) -> Result<()> {
+        self.state.storage().put("polls", polls).await.map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure updating polls in DO storage".to_string())
+        })
+    }
+
+    // The chat's creator moderates it, and admins and moderators can moderate every chat.
+    async fn is_moderator(&self, ws: &WebSocket, user_id: &str) -> bool {
+        let has_moderator_role = connection_attachments(ws).is_some_and(|attachments| {
+            attachments
+                .roles
+                .iter()
+                .any(|role| role == ADMIN_ROLE || role == MODERATOR_ROLE)
+        });
+
+        if has_moderator_role {
+            return true;
+        }
+
+        let chat_id = match self.state.storage().get::<String>("chat_id").await {
+            Ok(chat_id) => chat_id,
+            Err(_) => return false,
+        };
+
+        match self.chat_repository.get_chat(&chat_id).await {
+            Ok(chat) => chat.created_by == user_id,
+            Err(_) => false,
+        }
+    }
+
+    // Tells a muted user they cannot post to the room, returning whether they are muted.
+    async fn reject_if_muted(&self, ws: &WebSocket, user_id: &str) -> Result<bool> {
+        if !self.is_muted(user_id).await {
+            return Ok(false);
+        }
+
+        send_reply(
+            ws,
+            CommandReply::error("You are muted in this chatroom".to_string()),
+        )?;
+
+        Ok(true)
+    }
+
+    async fn is_muted(&self, user_id: &str) -> bool {
+        self.load_muted_users()
+            .await
+            .iter()
+            .any(|muted_user| muted_user == user_id)
+    }
+
+    async fn load_muted_users(&self) -> Vec<String> {
+        self.state
+            .storage()
+            .get::<Vec<String>>("muted_users")
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn save_muted_users(&self, muted_users: &[String]) -> Result<()> {
+        self.state
+            .storage()
+            .put("muted_users", muted_users)
+            .await
+            .map_err(|e| {
+                warn!("{}", e);
+                worker::Error::RustError("Failure updating muted users in DO storage".to_string())
+            })
+    }
+
+    fn broadcast<T: Serialize>(&mut self, message_wrapper: &MessageWrapper<T>) {
+        // Each frame is encoded once per encoding in use, not once per connection.
+        let mut encoded_frames: HashMap<FrameEncoding, EncodedFrame> = HashMap::new();
+        let now = Date::now().as_millis() / 1000;
+
+        for conn in self.state.get_websockets() {
+            // Nothing is sent to a connection after its token expires.
+            if token_has_expired(connection_token_expiry(&conn), now) {
+                close_expired_connection(&conn);
+                continue;
+            }
+
+            let encoding = connection_encoding(&conn);
+
+            let frame = match encoded_frames.entry(encoding) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match encoding.encode(message_wrapper) {
+                    Ok(frame) => entry.insert(frame),
+                    Err(e) => {
+                        warn!("{}", e);
+                        continue;
+                    }
+                },
+            };
+
+            let _ = frame.send(&conn);
+        }
+
+        let event = sse_event(message_wrapper);
+        let mut closed_users = Vec::new();
+
+        self.sse_subscribers.retain(|subscriber| {
+            if !token_has_expired(subscriber.token_expires_at, now)
+                && subscriber.sender.unbounded_send(event.clone()).is_ok()
+            {
+                return true;
+            }
+
+            closed_users.push(subscriber.user_id.clone());
+            false
+        });
+
+        self.closed_sse_users.extend(closed_users);
     }
 
     async fn load_messages(&mut self) -> Result<Vec<Message>> {
-        match self.state.storage().get::<Vec<Message>>(&self.messages_storage_key).await {
+        match self
+            .state
+            .storage()
+            .get::<Vec<Message>>(&self.messages_storage_key)
+            .await
+        {
             Ok(messages) => {
                 info!("Stored message count {}", messages.len());
                 Ok(messages)
-            },
+            }
             Err(e) => {
                 warn!("Error loading messages: {}", e);
                 let messages = Vec::new();
-                self.state.storage().put(&self.messages_storage_key, &messages).await?;
+                self.state
+                    .storage()
+                    .put(&self.messages_storage_key, &messages)
+                    .await?;
                 Ok(messages)
             }
         }
@@ -276,10 +1112,7 @@ impl Chatroom {
 
         let mut connections = current_connections.unwrap_or(0);
 
-        let mut users = match active_users {
-            Ok(users) => users,
-            Err(_) => vec![],
-        };
+        let mut users = active_users.unwrap_or_default();
 
         connections += match change_by {
             UpdateConnectionCountTypes::Increase => {
@@ -306,17 +1139,165 @@ impl Chatroom {
             ConnectionUpdate::new(connections, users),
         );
 
-        let web_socket_conns = self.state.get_websockets();
-
-        for conn in web_socket_conns {
-            let _ = conn.send(&message_wrapper);
-        }
+        self.broadcast(&message_wrapper);
 
         Ok(connections)
     }
 }
 
+fn connection_attachments(ws: &WebSocket) -> Option<WebsocketConnectionAttachments> {
+    ws.deserialize_attachment::<WebsocketConnectionAttachments>()
+        .ok()
+        .flatten()
+}
+
+fn connection_token_expiry(ws: &WebSocket) -> Option<u64> {
+    connection_attachments(ws).and_then(|attachments| attachments.token_expires_at)
+}
+
+// Both in seconds since the epoch. Bot tokens have no expiry.
+fn token_has_expired(token_expires_at: Option<u64>, now: u64) -> bool {
+    match token_expires_at {
+        Some(token_expires_at) => token_expires_at <= now,
+        None => false,
+    }
+}
+
+// When the alarm should next fire, in milliseconds since the epoch. Token expiries are in
+// seconds, as they are in the token. Connections whose token has already expired are being
+// closed, so they do not bring the alarm forward.
+fn next_alarm_at(chat_expires_at: u64, token_expiries: &[Option<u64>], now: u64) -> u64 {
+    let mut alarm_at = chat_expires_at;
+
+    for token_expires_at in token_expiries
+        .iter()
+        .flatten()
+        .filter(|token_expires_at| **token_expires_at * 1000 > now)
+    {
+        alarm_at = alarm_at
+            .min(token_expires_at * 1000)
+            .min(now + REVOCATION_CHECK_INTERVAL_IN_MILLIS);
+    }
+
+    alarm_at
+}
+
+fn close_expired_connection(ws: &WebSocket) {
+    info!("Closing connection with expired token");
+    let _ = ws.close(Some(TOKEN_EXPIRED_CLOSE_CODE), Some("Token expired"));
+}
+
+fn close_revoked_connection(ws: &WebSocket) {
+    info!("Closing connection with revoked token");
+    let _ = ws.close(Some(TOKEN_REVOKED_CLOSE_CODE), Some("Token revoked"));
+}
+
+fn connection_user_id(ws: &WebSocket) -> Result<String> {
+    let connection_attachments = ws
+        .deserialize_attachment::<WebsocketConnectionAttachments>()
+        .map_err(|e| {
+            warn!("{}", e);
+            worker::Error::RustError("Failure parsing attachments".to_string())
+        })?;
+
+    Ok(match connection_attachments {
+        Some(attachments) => attachments.user_id,
+        None => "".to_string(),
+    })
+}
+
+fn connection_encoding(ws: &WebSocket) -> FrameEncoding {
+    match connection_attachments(ws) {
+        Some(attachments) => attachments.encoding,
+        None => FrameEncoding::Json,
+    }
+}
+
+fn sse_event<T: Serialize>(message_wrapper: &MessageWrapper<T>) -> String {
+    format!(
+        "data: {}\n\n",
+        serde_json::to_string(message_wrapper).unwrap_or_default()
+    )
+}
+
+fn send_reply(ws: &WebSocket, reply: CommandReply) -> Result<()> {
+    connection_encoding(ws)
+        .encode(&MessageWrapper::new(MessageTypes::CommandReply, reply))?
+        .send(ws)
+}
+
 enum UpdateConnectionCountTypes {
     Increase,
     Decrease,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_IN_SECONDS: u64 = 1_700_000_000;
+    const NOW: u64 = NOW_IN_SECONDS * 1000;
+
+    #[test]
+    fn tokens_expire_at_their_exp() {
+        assert!(!token_has_expired(Some(NOW_IN_SECONDS + 1), NOW_IN_SECONDS));
+        assert!(token_has_expired(Some(NOW_IN_SECONDS), NOW_IN_SECONDS));
+        assert!(token_has_expired(Some(NOW_IN_SECONDS - 1), NOW_IN_SECONDS));
+    }
+
+    #[test]
+    fn tokens_without_an_expiry_never_expire() {
+        assert!(!token_has_expired(None, NOW_IN_SECONDS));
+    }
+
+    #[test]
+    fn an_empty_room_only_wakes_for_the_chat_expiry() {
+        let chat_expires_at = NOW + 300_000;
+
+        assert_eq!(next_alarm_at(chat_expires_at, &[], NOW), chat_expires_at);
+        assert_eq!(
+            next_alarm_at(chat_expires_at, &[None], NOW),
+            chat_expires_at
+        );
+    }
+
+    #[test]
+    fn the_alarm_fires_when_the_earliest_token_expires() {
+        let alarm_at = next_alarm_at(
+            NOW + 300_000,
+            &[Some(NOW_IN_SECONDS + 50), Some(NOW_IN_SECONDS + 20), None],
+            NOW,
+        );
+
+        assert_eq!(alarm_at, NOW + 20_000);
+    }
+
+    #[test]
+    fn connected_rooms_are_checked_for_revocations_regularly() {
+        let alarm_at = next_alarm_at(NOW + 300_000, &[Some(NOW_IN_SECONDS + 3600)], NOW);
+
+        assert_eq!(alarm_at, NOW + REVOCATION_CHECK_INTERVAL_IN_MILLIS);
+    }
+
+    #[test]
+    fn the_chat_expiry_wins_if_it_is_sooner() {
+        let alarm_at = next_alarm_at(NOW + 10_000, &[Some(NOW_IN_SECONDS + 20)], NOW);
+
+        assert_eq!(alarm_at, NOW + 10_000);
+    }
+
+    #[test]
+    fn expired_tokens_do_not_bring_the_alarm_forward() {
+        let alarm_at = next_alarm_at(NOW + 300_000, &[Some(NOW_IN_SECONDS - 5)], NOW);
+
+        assert_eq!(alarm_at, NOW + 300_000);
+    }
+
+    #[test]
+    fn only_tokens_with_an_id_can_be_checked_for_revocation() {
+        assert!(ConnectionToken::new(Some("jti".to_string()), Some(NOW_IN_SECONDS)).is_some());
+        assert!(ConnectionToken::new(Some(String::new()), Some(0)).is_none());
+        assert!(ConnectionToken::new(None, Some(NOW_IN_SECONDS)).is_none());
+        assert!(ConnectionToken::new(Some("jti".to_string()), None).is_none());
+    }
+}
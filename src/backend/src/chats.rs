@@ -1,18 +1,233 @@
-use serde::{Deserialize, Serialize};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use shared::errors::ApiError;
+use thiserror::Error;
 use tracing::{info, warn};
 use uuid::Uuid;
 use wasm_bindgen::JsValue;
-use worker::{kv::KvStore, D1Database};
+use worker::{kv::KvStore, D1Database, Date};
+
+const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_PAGE_SIZE: usize = 100;
+const CHATS_CACHE_PREFIX: &str = "CHATS:";
+// Cached pages of chats are keyed by the version stored here, so writing a new version drops every
+// page at once without having to find them.
+const CHATS_CACHE_VERSION_KEY: &str = "CHATS_VERSION";
+const MAX_TAGS_PER_CHAT: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_SEARCH_LENGTH: usize = 100;
+const MAX_NAME_LENGTH: usize = 100;
+// Chats can be given an expiry of between a minute and a day.
+const MIN_EXPIRY_IN_SECONDS: u64 = 60;
+const MAX_EXPIRY_IN_SECONDS: u64 = 86_400;
+
+// Tags are read back as a single comma separated column alongside each chat.
+const CHAT_COLUMNS: &str =
+    "c.id, c.name, c.created_by, c.created_at, c.last_activity_at, c.private,
+(SELECT group_concat(t.tag) FROM chat_tags t WHERE t.chat_id = c.id) AS tags";
+
+#[derive(Error, Debug)]
+pub enum ChatError {
+    #[error("Chat not found")]
+    NotFound,
+    #[error("A chat named {0} already exists")]
+    NameConflict(String),
+    #[error("{message}")]
+    Validation {
+        field: &'static str,
+        message: String,
+    },
+    #[error("Failure accessing chat storage: {0}")]
+    Storage(String),
+}
+
+impl From<worker::Error> for ChatError {
+    fn from(e: worker::Error) -> Self {
+        ChatError::Storage(e.to_string())
+    }
+}
+
+// Storage failures are logged rather than returned, as they can include D1 internals.
+impl From<ChatError> for ApiError {
+    fn from(e: ChatError) -> Self {
+        match e {
+            ChatError::NotFound => ApiError::not_found(e.to_string()),
+            ChatError::NameConflict(_) => {
+                ApiError::conflict(e.to_string()).with_field_error("name", "is already taken")
+            }
+            ChatError::Validation { field, message } => {
+                ApiError::validation(message.clone()).with_field_error(field, message)
+            }
+            ChatError::Storage(_) => {
+                warn!("{}", e);
+                ApiError::unavailable("Chat storage is unavailable, try again later")
+            }
+        }
+    }
+}
+
+impl ChatError {
+    fn validation(field: &'static str, message: impl Into<String>) -> Self {
+        ChatError::Validation {
+            field,
+            message: message.into(),
+        }
+    }
+
+    // D1 reports constraint violations as plain error messages.
+    fn from_write(e: worker::Error, name: &str) -> Self {
+        if e.to_string()
+            .contains("UNIQUE constraint failed: chats.name")
+        {
+            ChatError::NameConflict(name.to_string())
+        } else {
+            ChatError::from(e)
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct CreateChatCommand {
     pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub private: bool,
+}
+
+// Every field is optional, only those given are changed. The topic and expiry live in the
+// Chatroom rather than D1.
+#[derive(Deserialize)]
+pub struct UpdateChatCommand {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub expiry_in_seconds: Option<u64>,
+    #[serde(default)]
+    pub private: Option<bool>,
+}
+
+/// Lowercases a tag, returning `None` if it is empty, too long or contains anything other than
+/// letters, digits, `-` and `_`.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+
+    let is_valid = !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_LENGTH
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+
+    is_valid.then_some(tag)
+}
+
+/// Normalizes the tags given when creating a chat, dropping duplicates.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, ChatError> {
+    let mut normalized = Vec::new();
+
+    for tag in tags {
+        let tag = normalize_tag(tag).ok_or_else(|| {
+            ChatError::validation(
+                "tags",
+                format!(
+                    "Tag {} must be up to {} letters, digits, - and _",
+                    tag, MAX_TAG_LENGTH
+                ),
+            )
+        })?;
+
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    if normalized.len() > MAX_TAGS_PER_CHAT {
+        return Err(ChatError::validation(
+            "tags",
+            format!("Chats can have up to {} tags", MAX_TAGS_PER_CHAT),
+        ));
+    }
+
+    Ok(normalized)
+}
+
+pub fn validate_name(name: &str) -> Result<String, ChatError> {
+    let name = name.trim();
+
+    if name.is_empty() {
+        return Err(ChatError::validation("name", "Chat name must not be empty"));
+    }
+
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ChatError::validation(
+            "name",
+            format!("Chat name must be at most {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+
+    Ok(name.to_string())
+}
+
+pub fn validate_expiry(expiry_in_seconds: u64) -> Result<u64, ChatError> {
+    if !(MIN_EXPIRY_IN_SECONDS..=MAX_EXPIRY_IN_SECONDS).contains(&expiry_in_seconds) {
+        return Err(ChatError::validation(
+            "expiry_in_seconds",
+            format!(
+                "Chat expiry must be between {} and {} seconds",
+                MIN_EXPIRY_IN_SECONDS, MAX_EXPIRY_IN_SECONDS
+            ),
+        ));
+    }
+
+    Ok(expiry_in_seconds)
+}
+
+// D1 returns booleans as 0 or 1.
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(f64),
+    }
+
+    Ok(match Flag::deserialize(deserializer)? {
+        Flag::Bool(flag) => flag,
+        Flag::Number(number) => number != 0.0,
+    })
+}
+
+fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let tags = Option::<String>::deserialize(deserializer)?;
+
+    let mut tags: Vec<String> = tags
+        .unwrap_or_default()
+        .split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect();
+    tags.sort();
+
+    Ok(tags)
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ChatDTO {
     pub id: String,
     pub name: String,
+    #[serde(default)]
+    pub created_by: String,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub last_activity_at: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub private: bool,
 }
 
 impl ChatDTO {
@@ -20,6 +235,11 @@ impl ChatDTO {
         ChatDTO {
             id: chat.id.clone(),
             name: chat.name.clone(),
+            created_by: chat.created_by.clone(),
+            created_at: chat.created_at.clone(),
+            last_activity_at: chat.last_activity_at.clone(),
+            tags: chat.tags.clone(),
+            private: chat.private,
         }
     }
 }
@@ -29,18 +249,241 @@ pub struct Chat {
     pub id: String,
     pub name: String,
     pub created_by: String,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub last_activity_at: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub private: bool,
 }
 
 impl Chat {
-    pub fn new(name: String, created_by: String) -> Self {
+    pub fn new(name: String, created_by: String, tags: Vec<String>, private: bool) -> Self {
         Chat {
             id: Uuid::new_v4().to_string(),
             name,
             created_by,
+            created_at: None,
+            last_activity_at: None,
+            tags,
+            private,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatSort {
+    #[default]
+    Created,
+    Activity,
+    Name,
+}
+
+impl ChatSort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChatSort::Created => "created",
+            ChatSort::Activity => "activity",
+            ChatSort::Name => "name",
+        }
+    }
+
+    // Column names are never taken from the request, only from this list.
+    fn column(&self) -> &'static str {
+        match self {
+            ChatSort::Created => "c.created_at",
+            ChatSort::Activity => "c.last_activity_at",
+            ChatSort::Name => "c.name",
+        }
+    }
+
+    // Newest first for timestamps, alphabetical for names.
+    fn is_descending(&self) -> bool {
+        !matches!(self, ChatSort::Name)
+    }
+
+    fn value_of(&self, chat: &ChatDTO) -> String {
+        match self {
+            ChatSort::Created => chat.created_at.clone().unwrap_or_default(),
+            ChatSort::Activity => chat.last_activity_at.clone().unwrap_or_default(),
+            ChatSort::Name => chat.name.clone(),
         }
     }
 }
 
+#[derive(Deserialize)]
+pub struct ListChatsQuery {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub sort: ChatSort,
+    #[serde(default)]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+// Points at the last chat of a page. The sort value and id are both needed as names and
+// timestamps are not unique. Search results also carry whether the last chat was a prefix
+// match, as those are listed first.
+#[derive(Deserialize, Serialize)]
+struct ChatCursor {
+    value: String,
+    id: String,
+    #[serde(default)]
+    rank: u8,
+}
+
+impl ChatCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+pub struct ChatListOptions {
+    limit: usize,
+    sort: ChatSort,
+    cursor: Option<ChatCursor>,
+    created_by: Option<String>,
+    search: Option<String>,
+    tag: Option<String>,
+    include_private: bool,
+}
+
+impl ChatListOptions {
+    /// Returns `None` if the cursor was not one we handed out, or the search or tag are invalid.
+    /// Private chats are only listed when `viewer` asks for the chats they created.
+    pub fn from_query(query: ListChatsQuery, viewer: &str) -> Option<Self> {
+        let cursor = match &query.cursor {
+            Some(cursor) => Some(ChatCursor::decode(cursor)?),
+            None => None,
+        };
+
+        let search = query
+            .q
+            .map(|q| q.trim().to_string())
+            .filter(|q| !q.is_empty());
+
+        if search
+            .as_ref()
+            .is_some_and(|q| q.chars().count() > MAX_SEARCH_LENGTH)
+        {
+            return None;
+        }
+
+        let tag = match query.tag.filter(|tag| !tag.trim().is_empty()) {
+            Some(tag) => Some(normalize_tag(&tag)?),
+            None => None,
+        };
+
+        let created_by = query.created_by.filter(|created_by| !created_by.is_empty());
+        let include_private = created_by.as_deref() == Some(viewer);
+
+        Some(ChatListOptions {
+            limit: query
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            sort: query.sort,
+            cursor,
+            created_by,
+            search,
+            tag,
+            include_private,
+        })
+    }
+
+    fn cache_key(&self, version: &str) -> String {
+        // Serialized as JSON so user supplied values can never run into each other.
+        format!(
+            "{}{}:{}",
+            CHATS_CACHE_PREFIX,
+            version,
+            json!([
+                self.sort.as_str(),
+                self.limit,
+                self.cursor.as_ref().map(ChatCursor::encode),
+                self.created_by,
+                self.tag,
+                self.search,
+                self.include_private,
+            ])
+        )
+    }
+
+    // Conditions shared by listing and searching, binding their values onto the end of
+    // `bindings`.
+    fn filter_conditions(&self, bindings: &mut Vec<JsValue>) -> Vec<String> {
+        let mut conditions = Vec::new();
+
+        if !self.include_private {
+            conditions.push("c.private = 0".to_string());
+        }
+
+        if let Some(created_by) = &self.created_by {
+            bindings.push(JsValue::from(created_by));
+            conditions.push(format!("c.created_by = ?{}", bindings.len()));
+        }
+
+        if let Some(tag) = &self.tag {
+            bindings.push(JsValue::from(tag));
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM chat_tags t WHERE t.chat_id = c.id AND t.tag = ?{})",
+                bindings.len()
+            ));
+        }
+
+        conditions
+    }
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}\n", conditions.join(" AND "))
+    }
+}
+
+// Escapes the LIKE wildcards in user input, to be used with `ESCAPE '\'`.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Prefix matches rank ahead of other matches. SQLite's LIKE only ignores case for ASCII
+// characters, so this must too for the ranks in cursors to match the query.
+fn search_rank(name: &str, search: &str) -> u8 {
+    if name
+        .to_ascii_lowercase()
+        .starts_with(&search.to_ascii_lowercase())
+    {
+        0
+    } else {
+        1
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChatPage {
+    pub chats: Vec<ChatDTO>,
+    pub next_cursor: Option<String>,
+}
+
 pub struct ChatRepository {
     database: D1Database,
     cache: KvStore,
@@ -54,136 +497,346 @@ impl ChatRepository {
         }
     }
 
-    async fn list_from_db(&self, limit: usize) -> Vec<ChatDTO> {
-        info!("Cache miss");
-        let db_chats = &self
-            .database
-            .prepare(
-                "SELECT id, name, created_by
+    async fn list_from_db(&self, options: &ChatListOptions) -> Result<ChatPage, ChatError> {
+        let column = options.sort.column();
+        let (direction, comparison) = if options.sort.is_descending() {
+            ("DESC", "<")
+        } else {
+            ("ASC", ">")
+        };
+
+        let mut bindings = Vec::new();
+        let mut conditions = options.filter_conditions(&mut bindings);
+
+        if let Some(cursor) = &options.cursor {
+            bindings.push(JsValue::from(&cursor.value));
+            bindings.push(JsValue::from(&cursor.id));
+            conditions.push(format!(
+                "({}, c.id) {} (?{}, ?{})",
+                column,
+                comparison,
+                bindings.len() - 1,
+                bindings.len()
+            ));
+        }
+
+        // One extra row tells us whether there is another page.
+        bindings.push(JsValue::from(options.limit + 1));
+
+        let query = format!(
+            "SELECT {}
 FROM chats c
-LIMIT ?1",
-            )
-            .bind(&[JsValue::from(limit)])
-            .unwrap()
+{}ORDER BY {} {}, c.id {}
+LIMIT ?{}",
+            CHAT_COLUMNS,
+            where_clause(&conditions),
+            column,
+            direction,
+            direction,
+            bindings.len()
+        );
+
+        self.query_page(query, bindings, options.limit, |chat| ChatCursor {
+            value: options.sort.value_of(chat),
+            id: chat.id.clone(),
+            rank: 0,
+        })
+        .await
+    }
+
+    /// Finds chats whose name contains `search`, ignoring case. Names starting with it are
+    /// listed first, each group in name order, and the tag and creator filters still apply.
+    pub async fn search_chats(
+        &self,
+        search: &str,
+        options: &ChatListOptions,
+    ) -> Result<ChatPage, ChatError> {
+        let escaped = escape_like(search);
+
+        let mut bindings = vec![
+            JsValue::from(format!("{}%", escaped)),
+            JsValue::from(format!("%{}%", escaped)),
+        ];
+        let rank = "CASE WHEN c.name LIKE ?1 ESCAPE '\\' THEN 0 ELSE 1 END";
+
+        let mut conditions = vec!["c.name LIKE ?2 ESCAPE '\\'".to_string()];
+        conditions.extend(options.filter_conditions(&mut bindings));
+
+        if let Some(cursor) = &options.cursor {
+            bindings.push(JsValue::from(cursor.rank));
+            bindings.push(JsValue::from(&cursor.value));
+            bindings.push(JsValue::from(&cursor.id));
+            conditions.push(format!(
+                "({}, c.name, c.id) > (?{}, ?{}, ?{})",
+                rank,
+                bindings.len() - 2,
+                bindings.len() - 1,
+                bindings.len()
+            ));
+        }
+
+        bindings.push(JsValue::from(options.limit + 1));
+
+        let query = format!(
+            "SELECT {}
+FROM chats c
+{}ORDER BY {}, c.name, c.id
+LIMIT ?{}",
+            CHAT_COLUMNS,
+            where_clause(&conditions),
+            rank,
+            bindings.len()
+        );
+
+        self.query_page(query, bindings, options.limit, |chat| ChatCursor {
+            value: chat.name.clone(),
+            id: chat.id.clone(),
+            rank: search_rank(&chat.name, search),
+        })
+        .await
+    }
+
+    // Runs a query that selected one more chat than `limit`, using the extra row to decide
+    // whether a cursor for the next page is needed.
+    async fn query_page(
+        &self,
+        query: String,
+        bindings: Vec<JsValue>,
+        limit: usize,
+        cursor_for: impl Fn(&ChatDTO) -> ChatCursor,
+    ) -> Result<ChatPage, ChatError> {
+        let mut chats: Vec<ChatDTO> = self
+            .database
+            .prepare(query)
+            .bind(&bindings)?
             .all()
-            .await;
-
-        match db_chats {
-            Ok(d1_result) => {
-                let db_result = d1_result
-                    .results::<Chat>()
-                    .unwrap()
-                    .iter()
-                    .map(ChatDTO::from)
-                    .collect();
-
-                let res = &self
-                    .cache
-                    .put("CHATS", &db_result)
-                    .unwrap()
-                    // TTL in workers must be at least 60 seconds
-                    .expiration_ttl(60)
-                    .execute()
-                    .await;
-
-                match res {
-                    Ok(_) => {}
-                    Err(e) => warn!("Failure writing to cache: {:?}", e),
+            .await?
+            .results::<Chat>()?
+            .iter()
+            .map(ChatDTO::from)
+            .collect();
+
+        let next_cursor = if chats.len() > limit {
+            chats.truncate(limit);
+            chats.last().map(|chat| cursor_for(chat).encode())
+        } else {
+            None
+        };
+
+        Ok(ChatPage { chats, next_cursor })
+    }
+
+    pub async fn list_chats(&self, options: &ChatListOptions) -> Result<ChatPage, ChatError> {
+        let cache_key = options.cache_key(&self.cached_lists_version().await);
+
+        if let Ok(Some(page)) = self.cache.get(&cache_key).json::<ChatPage>().await {
+            info!("Cache hit");
+            return Ok(page);
+        }
+
+        info!("Cache miss");
+
+        let page = match &options.search {
+            Some(search) => self.search_chats(search, options).await?,
+            None => self.list_from_db(options).await?,
+        };
+
+        // A failure to cache the page is not a failure to list the chats.
+        match self.cache.put(&cache_key, &page) {
+            Ok(put) => {
+                // TTL in workers must be at least 60 seconds
+                if let Err(e) = put.expiration_ttl(60).execute().await {
+                    warn!("Failure writing to cache: {:?}", e);
                 }
+            }
+            Err(e) => warn!("Failure writing to cache: {:?}", e),
+        }
+
+        Ok(page)
+    }
 
-                db_result
+    // Until a chat is first added or removed, pages are cached under an empty version.
+    async fn cached_lists_version(&self) -> String {
+        match self.cache.get(CHATS_CACHE_VERSION_KEY).text().await {
+            Ok(version) => version.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failure reading cached chats version: {:?}", e);
+                String::new()
             }
-            Err(_) => Vec::new(),
         }
     }
 
-    pub async fn list_all_chats(&self, limit: usize) -> Vec<ChatDTO> {
-        let cached_chats = &self.cache.get("CHATS").json::<Vec<ChatDTO>>().await;
+    // Pages are cached per query, so every cached page is dropped whenever a chat is added or
+    // removed, by moving on to a new version. Pages cached under the old one are left to expire
+    // with the cache TTL. Activity updates are left to expire with the cache TTL too.
+    async fn invalidate_cached_lists(&self) {
+        let version = Date::now().as_millis().to_string();
 
-        match cached_chats {
-            Ok(cached) => {
-                info!("Cached chats found");
-                match cached {
-                    Some(value) => {
-                        info!("Cache hit");
-                        value.to_vec()
-                    }
-                    None => self.list_from_db(limit).await,
+        match self.cache.put(CHATS_CACHE_VERSION_KEY, version) {
+            Ok(put) => {
+                if let Err(e) = put.execute().await {
+                    warn!("Failure clearing cached chats: {:?}", e);
                 }
             }
-            Err(_) => self.list_from_db(limit).await,
+            Err(e) => warn!("Failure clearing cached chats: {:?}", e),
         }
     }
 
-    pub async fn get_chat(&self, id: &str) -> Result<ChatDTO, ()> {
-        let db_chats = &self
-            .database
+    pub async fn record_activity(&self, chat_id: &str) -> Result<(), ChatError> {
+        self.database
             .prepare(
-                "SELECT id, name, created_by
+                "UPDATE chats
+SET last_activity_at = CURRENT_TIMESTAMP
+WHERE id = ?1",
+            )
+            .bind(&[JsValue::from(chat_id)])?
+            .run()
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_chat(&self, id: &str) -> Result<ChatDTO, ChatError> {
+        let chat = self
+            .database
+            .prepare(format!(
+                "SELECT {}
 FROM chats c
 WHERE c.id = ?1",
-            )
-            .bind(&[JsValue::from(id)])
-            .unwrap()
+                CHAT_COLUMNS
+            ))
+            .bind(&[JsValue::from(id)])?
             .first::<Chat>(None)
-            .await;
+            .await?;
 
-        match db_chats {
-            Ok(d1_result) => match d1_result {
-                None => Err(()),
-                Some(chat) => Ok(ChatDTO::from(chat)),
-            },
-            Err(_) => Err(()),
+        match chat {
+            Some(chat) => Ok(ChatDTO::from(&chat)),
+            None => Err(ChatError::NotFound),
         }
     }
 
-    pub async fn delete_chat(&self, chat_id: &String) -> Result<(), ()> {
-        let _ = &self
+    pub async fn update_chat(
+        &self,
+        chat_id: &str,
+        name: Option<String>,
+        private: Option<bool>,
+    ) -> Result<ChatDTO, ChatError> {
+        let name = name.as_deref().map(validate_name).transpose()?;
+
+        let result = self
             .database
             .prepare(
-                "DELETE FROM chats
+                "UPDATE chats
+SET name = COALESCE(?2, name), private = COALESCE(?3, private)
 WHERE id = ?1",
             )
-            .bind(&[JsValue::from(chat_id)])
-            .unwrap()
+            .bind(&[
+                JsValue::from(chat_id),
+                name.as_ref().map(JsValue::from).unwrap_or(JsValue::NULL),
+                private.map(JsValue::from).unwrap_or(JsValue::NULL),
+            ])?
             .run()
-            .await;
+            .await
+            .map_err(|e| ChatError::from_write(e, name.as_deref().unwrap_or_default()))?;
+
+        if result.meta()?.and_then(|meta| meta.changes) == Some(0) {
+            return Err(ChatError::NotFound);
+        }
+
+        self.invalidate_cached_lists().await;
+
+        self.get_chat(chat_id).await
+    }
 
-        let _ = &self.cache.delete("CHATS").await;
+    /// Deletes the chat along with its tags and bot tokens, in one batch so none of them outlive
+    /// it. Its webhooks are kept until the webhook-delivery worker has delivered `chat.ended` to
+    /// them, and removed by that worker.
+    pub async fn delete_chat(&self, chat_id: &str) -> Result<(), ChatError> {
+        let statements = [
+            "DELETE FROM chats
+WHERE id = ?1",
+            "DELETE FROM chat_tags
+WHERE chat_id = ?1",
+            "DELETE FROM chat_bot_tokens
+WHERE chat_id = ?1",
+        ]
+        .iter()
+        .map(|statement| {
+            self.database
+                .prepare(*statement)
+                .bind(&[JsValue::from(chat_id)])
+        })
+        .collect::<worker::Result<Vec<_>>>()?;
+
+        let results = self.database.batch(statements).await?;
+
+        self.invalidate_cached_lists().await;
+
+        let deleted_chats = match results.first() {
+            Some(result) => result.meta()?.and_then(|meta| meta.changes),
+            None => None,
+        };
+
+        if deleted_chats == Some(0) {
+            return Err(ChatError::NotFound);
+        }
 
         Ok(())
     }
 
-    pub async fn add_chat(&self, chat: Chat) -> Result<Chat, ()> {
-        let insert_result = &self
+    pub async fn add_chat(&self, chat: Chat) -> Result<Chat, ChatError> {
+        let name = validate_name(&chat.name)?;
+        let tags = normalize_tags(&chat.tags)?;
+
+        let inserted = self
             .database
             .prepare(
                 "INSERT INTO chats
-            (id, name, created_by)
+            (id, name, created_by, created_at, last_activity_at, private)
             VALUES
-            (?1, ?2, ?3)
+            (?1, ?2, ?3, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?4)
             RETURNING *;",
             )
             .bind(&[
                 JsValue::from(chat.id),
-                JsValue::from(chat.name),
+                JsValue::from(&name),
                 JsValue::from(chat.created_by),
-            ])
-            .unwrap()
+                JsValue::from(chat.private),
+            ])?
             .first::<Chat>(None)
-            .await;
+            .await
+            .map_err(|e| ChatError::from_write(e, &name))?;
 
-        match insert_result {
-            Ok(res) => match res {
-                None => Err(()),
-                Some(chat) => {
-                    let _ = &self.cache.delete("CHATS").await;
+        let mut chat = inserted
+            .ok_or_else(|| ChatError::Storage("Inserted chat was not returned".to_string()))?;
 
-                    let cloned_chat = chat.clone();
-                    Ok(cloned_chat)
-                }
-            },
-            Err(_) => Err(()),
+        if !tags.is_empty() {
+            self.add_tags(&chat.id, &tags).await?;
+            chat.tags = tags;
         }
+
+        self.invalidate_cached_lists().await;
+
+        Ok(chat)
+    }
+
+    async fn add_tags(&self, chat_id: &str, tags: &[String]) -> Result<(), ChatError> {
+        let statements = tags
+            .iter()
+            .map(|tag| {
+                self.database
+                    .prepare(
+                        "INSERT INTO chat_tags
+            (chat_id, tag)
+            VALUES
+            (?1, ?2)",
+                    )
+                    .bind(&[JsValue::from(chat_id), JsValue::from(tag)])
+            })
+            .collect::<worker::Result<Vec<_>>>()?;
+
+        self.database.batch(statements).await?;
+
+        Ok(())
     }
 }
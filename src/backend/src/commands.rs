@@ -0,0 +1,243 @@
+use thiserror::Error;
+
+use crate::messaging::CreatePoll;
+
+pub struct CommandDefinition {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub moderator_only: bool,
+}
+
+// Every command the chatroom understands. The parser and `/help` both read from this list, so a
+// command only needs registering here and handling in `Chatroom::handle_command`.
+pub const REGISTERED_COMMANDS: &[CommandDefinition] = &[
+    CommandDefinition {
+        name: "me",
+        usage: "/me <action>",
+        description: "Describe an action, e.g. /me waves",
+        moderator_only: false,
+    },
+    CommandDefinition {
+        name: "topic",
+        usage: "/topic <topic>",
+        description: "Set the topic of the chatroom",
+        moderator_only: false,
+    },
+    CommandDefinition {
+        name: "poll",
+        usage: "/poll <question> | <option> | <option>",
+        description: "Start a poll, separating the question and each option with |",
+        moderator_only: false,
+    },
+    CommandDefinition {
+        name: "kick",
+        usage: "/kick <user>",
+        description: "Disconnect a user from the chatroom",
+        moderator_only: true,
+    },
+    CommandDefinition {
+        name: "mute",
+        usage: "/mute <user>",
+        description: "Stop a user from sending messages",
+        moderator_only: true,
+    },
+    CommandDefinition {
+        name: "unmute",
+        usage: "/unmute <user>",
+        description: "Allow a muted user to send messages again",
+        moderator_only: true,
+    },
+    CommandDefinition {
+        name: "help",
+        usage: "/help",
+        description: "List the available commands",
+        moderator_only: false,
+    },
+];
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unknown command /{0}, type /help to see the available commands")]
+    Unknown(String),
+    #[error("Usage: {0}")]
+    MissingArgument(&'static str),
+    #[error("Usage: {0}")]
+    InvalidArgument(&'static str),
+    #[error("Only moderators can use /{0}")]
+    NotPermitted(&'static str),
+}
+
+pub enum ChatCommand {
+    Me(String),
+    Topic(String),
+    Poll(CreatePoll),
+    Kick(String),
+    Mute(String),
+    Unmute(String),
+    Help,
+}
+
+impl ChatCommand {
+    /// Returns `None` if the input is a plain message rather than a command.
+    pub fn parse(input: &str) -> Option<Result<ChatCommand, CommandError>> {
+        let input = input.trim();
+        let command_text = input.strip_prefix('/')?;
+
+        let (name, argument) = match command_text.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim().to_string()),
+            None => (command_text, String::new()),
+        };
+
+        let definition = match REGISTERED_COMMANDS.iter().find(|c| c.name == name) {
+            Some(definition) => definition,
+            None => return Some(Err(CommandError::Unknown(name.to_string()))),
+        };
+
+        if argument.is_empty() && definition.name != "help" {
+            return Some(Err(CommandError::MissingArgument(definition.usage)));
+        }
+
+        let command = match definition.name {
+            "me" => ChatCommand::Me(argument),
+            "topic" => ChatCommand::Topic(argument),
+            "poll" => {
+                let mut parts = argument.split('|').map(|part| part.trim().to_string());
+                let question = parts.next().unwrap_or_default();
+                let options: Vec<String> = parts.collect();
+
+                if options.len() < 2 {
+                    return Some(Err(CommandError::InvalidArgument(definition.usage)));
+                }
+
+                ChatCommand::Poll(CreatePoll { question, options })
+            }
+            "kick" => ChatCommand::Kick(argument),
+            "mute" => ChatCommand::Mute(argument),
+            "unmute" => ChatCommand::Unmute(argument),
+            _ => ChatCommand::Help,
+        };
+
+        Some(Ok(command))
+    }
+
+    pub fn definition(&self) -> &'static CommandDefinition {
+        let name = match self {
+            ChatCommand::Me(_) => "me",
+            ChatCommand::Topic(_) => "topic",
+            ChatCommand::Poll(_) => "poll",
+            ChatCommand::Kick(_) => "kick",
+            ChatCommand::Mute(_) => "mute",
+            ChatCommand::Unmute(_) => "unmute",
+            ChatCommand::Help => "help",
+        };
+
+        REGISTERED_COMMANDS
+            .iter()
+            .find(|c| c.name == name)
+            .expect("every command is registered")
+    }
+}
+
+pub fn help_text() -> String {
+    REGISTERED_COMMANDS
+        .iter()
+        .map(|c| {
+            if c.moderator_only {
+                format!("{} - {} (moderators only)", c.usage, c.description)
+            } else {
+                format!("{} - {}", c.usage, c.description)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> ChatCommand {
+        match ChatCommand::parse(input) {
+            Some(Ok(command)) => command,
+            Some(Err(e)) => panic!("{} did not parse: {}", input, e),
+            None => panic!("{} is not a command", input),
+        }
+    }
+
+    #[test]
+    fn plain_messages_are_not_commands() {
+        assert!(ChatCommand::parse("Hello there").is_none());
+        assert!(ChatCommand::parse("and/or").is_none());
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_argument() {
+        assert!(matches!(parsed("/me waves"), ChatCommand::Me(action) if action == "waves"));
+        assert!(matches!(
+            parsed("  /topic   Rust on Workers  "),
+            ChatCommand::Topic(topic) if topic == "Rust on Workers"
+        ));
+        assert!(matches!(parsed("/kick jane"), ChatCommand::Kick(user) if user == "jane"));
+        assert!(matches!(parsed("/mute jane"), ChatCommand::Mute(user) if user == "jane"));
+        assert!(matches!(parsed("/unmute jane"), ChatCommand::Unmute(user) if user == "jane"));
+        assert!(matches!(parsed("/help"), ChatCommand::Help));
+    }
+
+    #[test]
+    fn polls_are_split_into_a_question_and_options() {
+        match parsed("/poll Lunch? | Pizza | Tacos ") {
+            ChatCommand::Poll(poll) => {
+                assert_eq!(poll.question, "Lunch?");
+                assert_eq!(poll.options, vec!["Pizza", "Tacos"]);
+            }
+            _ => panic!("not a poll"),
+        }
+
+        assert!(matches!(
+            ChatCommand::parse("/poll Lunch? | Pizza"),
+            Some(Err(CommandError::InvalidArgument(_)))
+        ));
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        assert!(matches!(
+            ChatCommand::parse("/shrug"),
+            Some(Err(CommandError::Unknown(name))) if name == "shrug"
+        ));
+    }
+
+    #[test]
+    fn commands_other_than_help_need_an_argument() {
+        for input in ["/me", "/topic  ", "/poll", "/kick", "/mute", "/unmute"] {
+            assert!(
+                matches!(
+                    ChatCommand::parse(input),
+                    Some(Err(CommandError::MissingArgument(_)))
+                ),
+                "{} was accepted",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn commands_know_their_definition() {
+        assert_eq!(parsed("/me waves").definition().name, "me");
+        assert!(parsed("/kick jane").definition().moderator_only);
+        assert!(!parsed("/help").definition().moderator_only);
+    }
+
+    #[test]
+    fn help_lists_every_command_and_marks_moderator_commands() {
+        let help = help_text();
+
+        assert_eq!(help.lines().count(), REGISTERED_COMMANDS.len());
+        assert!(help.contains("/me <action> - Describe an action, e.g. /me waves"));
+        assert!(
+            help.contains("/kick <user> - Disconnect a user from the chatroom (moderators only)")
+        );
+        assert!(!help.contains("/help - List the available commands (moderators only)"));
+    }
+}
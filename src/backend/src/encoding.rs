@@ -0,0 +1,226 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use worker::WebSocket;
+
+#[derive(Error, Debug)]
+pub enum EncodingError {
+    #[error("Failure encoding frame: {0}")]
+    Encode(String),
+    #[error("Failure decoding frame: {0}")]
+    Decode(String),
+}
+
+impl From<EncodingError> for worker::Error {
+    fn from(e: EncodingError) -> Self {
+        worker::Error::RustError(e.to_string())
+    }
+}
+
+/// The wire format a WebSocket connection uses for every frame, chosen when it connects with
+/// either an `encoding` query parameter or a `Sec-WebSocket-Protocol` subprotocol.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FrameEncoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+pub enum EncodedFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl EncodedFrame {
+    pub fn send(&self, ws: &WebSocket) -> worker::Result<()> {
+        match self {
+            EncodedFrame::Text(text) => ws.send_with_str(text),
+            EncodedFrame::Binary(bytes) => ws.send_with_bytes(bytes),
+        }
+    }
+}
+
+impl FrameEncoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "json" => Some(FrameEncoding::Json),
+            "msgpack" | "messagepack" => Some(FrameEncoding::MessagePack),
+            "cbor" => Some(FrameEncoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Picks the encoding for a new connection from the `encoding` query parameter, or failing
+    /// that the first `chat.*` subprotocol we support, falling back to JSON. Browsers fail the
+    /// handshake unless one of the subprotocols they offered is echoed back in the upgrade
+    /// response, so if any were offered one of them is returned as well. That is the one naming
+    /// the chosen encoding if there is one, and otherwise the first offered.
+    pub fn negotiate<'a>(
+        query_encoding: Option<&str>,
+        subprotocols: Option<&'a str>,
+    ) -> (FrameEncoding, Option<&'a str>) {
+        let offered: Vec<&str> = subprotocols
+            .unwrap_or_default()
+            .split(',')
+            .map(|protocol| protocol.trim())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+
+        let offered_encoding = |protocol: &str| {
+            protocol
+                .strip_prefix("chat.")
+                .and_then(FrameEncoding::from_name)
+        };
+
+        let encoding = query_encoding
+            .and_then(FrameEncoding::from_name)
+            .or_else(|| {
+                offered
+                    .iter()
+                    .find_map(|protocol| offered_encoding(protocol))
+            })
+            .unwrap_or_default();
+
+        let subprotocol = offered
+            .iter()
+            .find(|protocol| offered_encoding(protocol) == Some(encoding))
+            .or(offered.first())
+            .copied();
+
+        (encoding, subprotocol)
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<EncodedFrame, EncodingError> {
+        match self {
+            FrameEncoding::Json => serde_json::to_string(value)
+                .map(EncodedFrame::Text)
+                .map_err(|e| EncodingError::Encode(e.to_string())),
+            // Named encoding keeps field names in the output, so frames have the same shape as
+            // their JSON equivalents.
+            FrameEncoding::MessagePack => rmp_serde::to_vec_named(value)
+                .map(EncodedFrame::Binary)
+                .map_err(|e| EncodingError::Encode(e.to_string())),
+            FrameEncoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)
+                    .map_err(|e| EncodingError::Encode(e.to_string()))?;
+                Ok(EncodedFrame::Binary(bytes))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            FrameEncoding::Json => {
+                serde_json::from_slice(bytes).map_err(|e| EncodingError::Decode(e.to_string()))
+            }
+            FrameEncoding::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| EncodingError::Decode(e.to_string()))
+            }
+            FrameEncoding::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| EncodingError::Decode(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::{Message, MessageTypes, MessageWrapper};
+
+    #[test]
+    fn the_query_parameter_wins_over_subprotocols() {
+        assert_eq!(
+            FrameEncoding::negotiate(Some("cbor"), Some("chat.msgpack, chat.cbor")),
+            (FrameEncoding::Cbor, Some("chat.cbor"))
+        );
+        assert_eq!(
+            FrameEncoding::negotiate(Some("msgpack"), None),
+            (FrameEncoding::MessagePack, None)
+        );
+    }
+
+    #[test]
+    fn the_first_supported_subprotocol_is_chosen() {
+        assert_eq!(
+            FrameEncoding::negotiate(None, Some("chat.xml, chat.msgpack, chat.cbor")),
+            (FrameEncoding::MessagePack, Some("chat.msgpack"))
+        );
+    }
+
+    #[test]
+    fn unknown_encodings_fall_back_to_json() {
+        assert_eq!(
+            FrameEncoding::negotiate(Some("xml"), None),
+            (FrameEncoding::Json, None)
+        );
+        assert_eq!(
+            FrameEncoding::negotiate(Some("xml"), Some("chat.msgpack")),
+            (FrameEncoding::MessagePack, Some("chat.msgpack"))
+        );
+        assert_eq!(
+            FrameEncoding::negotiate(None, Some("chat.xml")),
+            (FrameEncoding::Json, Some("chat.xml"))
+        );
+    }
+
+    #[test]
+    fn an_offered_protocol_is_always_echoed() {
+        assert_eq!(
+            FrameEncoding::negotiate(None, Some("bearer.abc")),
+            (FrameEncoding::Json, Some("bearer.abc"))
+        );
+        assert_eq!(
+            FrameEncoding::negotiate(Some("cbor"), Some("bearer.abc, chat.json")),
+            (FrameEncoding::Cbor, Some("bearer.abc"))
+        );
+        assert_eq!(
+            FrameEncoding::negotiate(None, None),
+            (FrameEncoding::Json, None)
+        );
+    }
+
+    #[test]
+    fn frames_round_trip_in_every_encoding() {
+        let frame = MessageWrapper::new(
+            MessageTypes::NewMessage,
+            Message::text("jane".to_string(), "Hello there".to_string()),
+        );
+        let expected = serde_json::to_value(&frame).unwrap();
+
+        for encoding in [
+            FrameEncoding::Json,
+            FrameEncoding::MessagePack,
+            FrameEncoding::Cbor,
+        ] {
+            let bytes = match encoding.encode(&frame).unwrap() {
+                EncodedFrame::Text(text) => {
+                    assert_eq!(encoding, FrameEncoding::Json);
+                    text.into_bytes()
+                }
+                EncodedFrame::Binary(bytes) => bytes,
+            };
+
+            let decoded: MessageWrapper<Message> = encoding.decode(&bytes).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn frames_in_another_encoding_are_rejected() {
+        let frame = MessageWrapper::new(
+            MessageTypes::NewMessage,
+            Message::text("jane".to_string(), "Hello there".to_string()),
+        );
+
+        let bytes = match FrameEncoding::MessagePack.encode(&frame).unwrap() {
+            EncodedFrame::Binary(bytes) => bytes,
+            EncodedFrame::Text(_) => unreachable!(),
+        };
+
+        assert!(FrameEncoding::Json
+            .decode::<MessageWrapper<Message>>(&bytes)
+            .is_err());
+    }
+}
@@ -1,47 +1,77 @@
-use auth::{AuthenticationService, Claims};
-use chats::{Chat, ChatRepository, CreateChatCommand};
+use bots::{BotTokenRepository, CreateBotTokenCommand, PostMessageCommand};
+use chats::{
+    validate_expiry, Chat, ChatDTO, ChatError, ChatListOptions, ChatRepository, CreateChatCommand,
+    ListChatsQuery, UpdateChatCommand,
+};
+use messaging::{PostedMessage, RoomSettings};
+use routes::{Access, RouteDefinition};
 use serde::Deserialize;
-use tracing::warn;
-use tracing_subscriber::{
-    fmt::{format::Pretty, time::UtcTime},
-    prelude::*,
+use shared::{
+    auth::{AuthenticationService, Claims},
+    errors::{finalize_response, request_id, set_panic_hook, ApiError, ErrorCode},
+    revocation::RevocationStore,
+    telemetry,
 };
-use tracing_web::{performance_layer, MakeConsoleWriter};
+use tickets::ConnectTicketRepository;
+use tracing::warn;
+use wasm_bindgen::JsValue;
+use webhooks::{RegisterWebhookCommand, Webhook, WebhookRepository};
 use worker::*;
 
-mod auth;
+mod bots;
 mod chatroom;
 mod chats;
+mod commands;
+mod encoding;
 mod messaging;
+mod routes;
+mod tickets;
+mod webhooks;
 
 #[derive(Deserialize)]
 struct QueryStringParameters {
-    key: String,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    ticket: Option<String>,
+    #[serde(default)]
+    encoding: Option<String>,
 }
 
 #[event(start)]
 fn start() {
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .json()
-        .with_ansi(false) // Only partially supported across JavaScript runtimes
-        .with_timer(UtcTime::rfc_3339()) // std::time is not available in browsers
-        .with_writer(MakeConsoleWriter); // write events to the console
-    let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(perf_layer)
-        .init();
+    telemetry::init();
 }
 
 pub struct AppState {
     chat_repository: ChatRepository,
-    auth_service: AuthenticationService
+    webhook_repository: WebhookRepository,
+    bot_token_repository: BotTokenRepository,
+    ticket_repository: ConnectTicketRepository,
+    auth_service: AuthenticationService,
+    revocation_store: RevocationStore,
+    allow_query_string_tokens: bool,
+    request_id: String,
+}
+
+impl AppState {
+    // Every error a handler returns goes through here, so it carries the request ID.
+    fn error(&self, error: ApiError) -> Result<Response> {
+        error.into_response(&self.request_id)
+    }
 }
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    console_error_panic_hook::set_once();
+    set_panic_hook();
 
+    let request_id = request_id(&req);
+    let result = route(req, env, request_id.clone()).await;
+
+    finalize_response(result, &request_id).await
+}
+
+async fn route(req: Request, env: Env, request_id: String) -> Result<Response> {
     let database_binding = env.d1("CHAT_METADATA").map_err(|e| {
         warn!("{}", e);
         worker::Error::RustError("CHAT_METADATA binding not found".to_string())
@@ -52,142 +82,694 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         worker::Error::RustError("CHAT_CACHE binding not found".to_string())
     })?;
 
-    let jwt_secret = env.secret("JWT_SECRET")?.to_string();
+    let webhook_database_binding = env.d1("CHAT_METADATA")?;
+    let bot_token_database_binding = env.d1("CHAT_METADATA")?;
 
-    Router::with_data(AppState {
+    let ticket_database_binding = env.d1("CHAT_METADATA")?;
+
+    let revoked_tokens_binding = env.kv("REVOKED_TOKENS").map_err(|e| {
+        warn!("{}", e);
+        worker::Error::RustError("REVOKED_TOKENS binding not found".to_string())
+    })?;
+
+    // Tokens are verified with the keys the authentication worker publishes.
+    let authentication_binding = env.service("AUTH")?;
+
+    // Passing the JWT as `?key=` is deprecated, set this to "false" to reject it.
+    let allow_query_string_tokens = env
+        .var("ALLOW_QUERY_STRING_TOKENS")
+        .map(|value| value.to_string() != "false")
+        .unwrap_or(true);
+
+    let router = Router::with_data(AppState {
         chat_repository: ChatRepository::new(database_binding, cache_binding),
-        auth_service: AuthenticationService::new(jwt_secret)
-        
-    })
-    .on_async("/api/connect/:chat_id", handle_websocket_connect)
-    .get_async("/api/chats", handle_get_active_chats)
-    .get_async("/api/chats/:chat_id", handle_get_specific_chat)
-    .post_async("/api/chats", handle_create_new_chat)
-    .run(req, env)
-    .await
+        webhook_repository: WebhookRepository::new(webhook_database_binding),
+        bot_token_repository: BotTokenRepository::new(bot_token_database_binding),
+        ticket_repository: ConnectTicketRepository::new(ticket_database_binding),
+        auth_service: AuthenticationService::verifier(authentication_binding),
+        revocation_store: RevocationStore::new(revoked_tokens_binding),
+        allow_query_string_tokens,
+        request_id,
+    });
+
+    routes()
+        .into_iter()
+        .fold(router, |router, route| route.register(router))
+        .run(req, env)
+        .await
+}
+
+// Every route the backend serves and how its caller is authenticated. A route only needs
+// registering here, the guard verifies the caller before the handler runs.
+fn routes() -> Vec<RouteDefinition> {
+    vec![
+        RouteDefinition::protected(
+            None,
+            "/api/connect/:chat_id",
+            Access::Connection,
+            handle_websocket_connect,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Post),
+            "/api/connect-ticket/:chat_id",
+            Access::Bearer,
+            handle_create_connect_ticket,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Get),
+            "/api/chats",
+            Access::Bearer,
+            handle_get_active_chats,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Get),
+            "/api/chats/:chat_id",
+            Access::Bearer,
+            handle_get_specific_chat,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Post),
+            "/api/chats",
+            Access::Bearer,
+            handle_create_new_chat,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Patch),
+            "/api/chats/:chat_id",
+            Access::Bearer,
+            handle_update_chat,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Delete),
+            "/api/chats/:chat_id",
+            Access::Bearer,
+            handle_delete_chat,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Get),
+            "/api/chats/:chat_id/events",
+            Access::Connection,
+            handle_subscribe_events,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Post),
+            "/api/chats/:chat_id/messages",
+            Access::BearerOrBot,
+            handle_post_message,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Get),
+            "/api/chats/:chat_id/bot-tokens",
+            Access::Bearer,
+            handle_list_bot_tokens,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Post),
+            "/api/chats/:chat_id/bot-tokens",
+            Access::Bearer,
+            handle_create_bot_token,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Delete),
+            "/api/chats/:chat_id/bot-tokens/:token_id",
+            Access::Bearer,
+            handle_delete_bot_token,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Get),
+            "/api/chats/:chat_id/webhooks",
+            Access::Bearer,
+            handle_list_webhooks,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Post),
+            "/api/chats/:chat_id/webhooks",
+            Access::Bearer,
+            handle_register_webhook,
+        ),
+        RouteDefinition::protected(
+            Some(Method::Delete),
+            "/api/chats/:chat_id/webhooks/:webhook_id",
+            Access::Bearer,
+            handle_delete_webhook,
+        ),
+    ]
 }
 
 pub async fn handle_get_active_chats(
     req: Request,
     ctx: RouteContext<AppState>,
+    claims: Claims,
 ) -> Result<Response> {
-    if let Err(_) = verify_jwt(&req, &ctx.data.auth_service) {
-        return Response::error("Unauthorized", 401);
-    }
-
-    let chats = ctx.data.chat_repository.list_all_chats(10).await;
+    // Unknown sort orders, non-numeric limits, invalid tags and cursors we did not issue are all
+    // rejected.
+    let options = match req
+        .query::<ListChatsQuery>()
+        .ok()
+        .and_then(|query| ChatListOptions::from_query(query, &claims.sub))
+    {
+        Some(options) => options,
+        None => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Invalid query parameters"))
+        }
+    };
 
-    Response::from_json(&chats)
+    match ctx.data.chat_repository.list_chats(&options).await {
+        Ok(chats) => Response::from_json(&chats),
+        Err(e) => ctx.data.error(e.into()),
+    }
 }
 
 pub async fn handle_create_new_chat(
     mut req: Request,
     ctx: RouteContext<AppState>,
+    claims: Claims,
 ) -> Result<Response> {
-    let claims = match verify_jwt(&req, &ctx.data.auth_service) {
-        Ok(claims) => claims,
-        Err(_) => return Response::error("Unauthorized", 401),
+    let command: CreateChatCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
     };
 
-    let command: CreateChatCommand = req.json().await.unwrap();
-
-    let chat = Chat::new(command.name, claims.sub);
-
-    let chat = ctx
-        .data
-        .chat_repository
-        .add_chat(chat)
-        .await
-        .map_err(|_e| Error::RustError("Failure creating chat".to_string()))?;
+    let chat = Chat::new(command.name, claims.sub, command.tags, command.private);
 
-    Response::from_json(&chat)
+    match ctx.data.chat_repository.add_chat(chat).await {
+        Ok(chat) => Response::from_json(&chat),
+        Err(e) => ctx.data.error(e.into()),
+    }
 }
 
 pub async fn handle_get_specific_chat(
-    req: Request,
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    match get_visible_chat(&ctx, &claims).await? {
+        Ok(chat) => Response::from_json(&chat),
+        Err(response) => Ok(response),
+    }
+}
+
+pub async fn handle_update_chat(
+    mut req: Request,
     ctx: RouteContext<AppState>,
+    claims: Claims,
 ) -> Result<Response> {
-    if let Err(_) = verify_jwt(&req, &ctx.data.auth_service) {
-        return Response::error("Unauthorized", 401);
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    let command: UpdateChatCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    if let Some(Err(e)) = command.expiry_in_seconds.map(validate_expiry) {
+        return ctx.data.error(e.into());
     }
 
-    if let Some(chat_id) = ctx.param("chat_id") {
-        let chat = ctx
+    let chat = if command.name.is_some() || command.private.is_some() {
+        match ctx
             .data
             .chat_repository
-            .get_chat(chat_id)
+            .update_chat(&chat.id, command.name, command.private)
             .await
-            .map_err(|_e| Error::RustError("Failure retrieving chat".to_string()))?;
+        {
+            Ok(chat) => chat,
+            Err(e) => return ctx.data.error(e.into()),
+        }
+    } else {
+        chat
+    };
 
-        return Response::from_json(&chat);
+    if command.topic.is_some() || command.expiry_in_seconds.is_some() {
+        let body = serde_json::to_string(&RoomSettings {
+            topic: command.topic,
+            expiry_in_seconds: command.expiry_in_seconds,
+            updated_by: claims.sub,
+        })?;
+
+        let mut new_url = req.url()?;
+        new_url.set_path(&format!("/api/room/{}", chat.id));
+
+        let new_req = Request::new_with_init(
+            new_url.as_str(),
+            RequestInit::new()
+                .with_method(Method::Patch)
+                .with_body(Some(JsValue::from_str(&body))),
+        )?;
+
+        let room_response = ctx
+            .durable_object("CHATROOM")?
+            .id_from_name(&chat.id)?
+            .get_stub()?
+            .fetch_with_request(new_req)
+            .await;
+
+        // The topic and expiry live in the Chatroom, so the update has not been applied unless
+        // it accepted them.
+        let status = room_response.map(|response| response.status_code());
+        if !matches!(status, Ok(200..=299)) {
+            warn!(
+                "Chatroom rejected the update to chat {}: {:?}",
+                chat.id, status
+            );
+            return ctx.data.error(ApiError::unavailable(
+                "Chat room is unavailable, try again later",
+            ));
+        }
+    }
+
+    Response::from_json(&chat)
+}
+
+pub async fn handle_delete_chat(
+    req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    if let Err(e) = ctx.data.chat_repository.delete_chat(&chat.id).await {
+        return ctx.data.error(e.into());
     }
 
-    Ok(Response::builder()
-        .with_status(40)
-        .body(ResponseBody::Empty))
+    // The Chatroom ends the room for anyone still connected and clears its storage.
+    let mut new_url = req.url()?;
+    new_url.set_path(&format!("/api/room/{}", chat.id));
+
+    let new_req = Request::new_with_init(
+        new_url.as_str(),
+        RequestInit::new().with_method(Method::Delete),
+    )?;
+
+    ctx.durable_object("CHATROOM")?
+        .id_from_name(&chat.id)?
+        .get_stub()?
+        .fetch_with_request(new_req)
+        .await?;
+
+    Ok(Response::empty()?.with_status(204))
 }
 
 pub async fn handle_websocket_connect(
     req: Request,
     ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let is_upgrade = matches!(
+        req.headers().get("Upgrade"),
+        Ok(Some(ref upgrade)) if upgrade.eq_ignore_ascii_case("websocket")
+    );
+
+    if !is_upgrade {
+        return ctx.data.error(ApiError::new(
+            ErrorCode::UpgradeRequired,
+            "Expected a WebSocket upgrade",
+        ));
+    }
+
+    let chat_id = match get_visible_chat(&ctx, &claims).await? {
+        Ok(chat) => chat.id,
+        Err(response) => return Ok(response),
+    };
+
+    let query = req.query::<QueryStringParameters>().ok();
+
+    let url = req.url()?;
+    let mut new_url = url.clone();
+    new_url.set_query(Some(&format!(
+        "user_id={}&token_exp={}&token_iat={}",
+        claims.sub, claims.exp, claims.iat
+    )));
+    new_url
+        .query_pairs_mut()
+        .append_pair("token_jti", &claims.jti);
+    new_url
+        .query_pairs_mut()
+        .append_pair("roles", &claims.roles.join(","));
+
+    if let Some(encoding) = query.and_then(|query| query.encoding) {
+        new_url.query_pairs_mut().append_pair("encoding", &encoding);
+    }
+
+    let mut new_req = Request::new(new_url.as_str(), req.method())?;
+    let _ = new_req.headers_mut()?.set("Upgrade", "websocket");
+
+    // The Chatroom negotiates the frame encoding from the offered subprotocols and echoes one of
+    // them. A client that offered nothing but its `bearer.<token>` gets that one echoed, so it is
+    // passed on too.
+    if let Some(subprotocols) = req.headers().get("Sec-WebSocket-Protocol")? {
+        let _ = new_req
+            .headers_mut()?
+            .set("Sec-WebSocket-Protocol", &subprotocols);
+    }
+
+    let object = ctx.durable_object("CHATROOM")?;
+    let id = object.id_from_name(&chat_id)?;
+    let stub = id.get_stub()?;
+
+    stub.fetch_with_request(new_req).await
+}
+
+pub async fn handle_create_connect_ticket(
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat_id = match ctx.param("chat_id") {
+        Some(chat_id) => chat_id.clone(),
+        None => return ctx.data.error(ApiError::bad_request("Missing chat id")),
+    };
+
+    if let Err(response) = get_visible_chat(&ctx, &claims).await? {
+        return Ok(response);
+    }
+
+    match ctx.data.ticket_repository.issue(chat_id, claims).await {
+        Ok(ticket) => Response::from_json(&ticket),
+        Err(e) => ctx.data.error(e.into()),
+    }
+}
+
+// Server-Sent Events fallback for clients whose networks block WebSocket upgrades.
+pub async fn handle_subscribe_events(
+    req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat_id = match ctx.param("chat_id") {
+        Some(chat_id) => chat_id.clone(),
+        None => return ctx.data.error(ApiError::bad_request("Missing chat id")),
+    };
+
+    if let Err(response) = get_visible_chat(&ctx, &claims).await? {
+        return Ok(response);
+    }
+
+    let mut new_url = req.url()?;
+    new_url.set_path(&format!("/api/events/{}", chat_id));
+    new_url.set_query(Some(&format!(
+        "user_id={}&token_exp={}&token_iat={}",
+        claims.sub, claims.exp, claims.iat
+    )));
+    new_url
+        .query_pairs_mut()
+        .append_pair("token_jti", &claims.jti);
+
+    let object = ctx.durable_object("CHATROOM")?;
+    let id = object.id_from_name(&chat_id)?;
+    let stub = id.get_stub()?;
+
+    stub.fetch_with_str(new_url.as_str()).await
+}
+
+// Messages can be posted either by a user with their JWT, which is how clients on the SSE
+// transport send messages, or by an integration using a bot token issued for the chat with
+// `Authorization: Bot <token>`.
+pub async fn handle_post_message(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
 ) -> Result<Response> {
-    let upgrade_header = req.headers().get("Upgrade");
+    let chat_id = match ctx.param("chat_id") {
+        Some(chat_id) => chat_id.clone(),
+        None => return ctx.data.error(ApiError::bad_request("Missing chat id")),
+    };
+
+    let is_bot = claims.is_bot();
+
+    if let Err(response) = get_visible_chat(&ctx, &claims).await? {
+        return Ok(response);
+    }
+
+    let command: PostMessageCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
 
-    if upgrade_header.is_err() || upgrade_header.unwrap().unwrap() != "websocket" {
-        return Ok(Response::builder()
-            .with_status(426)
-            .body(ResponseBody::Empty));
+    if command.contents.trim().is_empty() {
+        return ctx.data.error(
+            ApiError::validation("Message contents must not be empty")
+                .with_field_error("contents", "must not be empty"),
+        );
     }
 
-    if let Some(chat_id) = ctx.param("chat_id") {
-        let password_header = req.query::<QueryStringParameters>();
+    let mut new_url = req.url()?;
+    new_url.set_path(&format!("/api/messages/{}", chat_id));
+    new_url.set_query(None);
+
+    let body = serde_json::to_string(&PostedMessage {
+        user: claims.sub,
+        contents: command.contents,
+        bot: is_bot,
+    })?;
 
-        if password_header.is_err() {
-            return Ok(Response::builder()
-                .with_status(401)
-                .body(ResponseBody::Empty));
+    let new_req = Request::new_with_init(
+        new_url.as_str(),
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(JsValue::from_str(&body))),
+    )?;
+
+    let object = ctx.durable_object("CHATROOM")?;
+    let id = object.id_from_name(&chat_id)?;
+    let stub = id.get_stub()?;
+
+    stub.fetch_with_request(new_req).await
+}
+
+pub async fn handle_list_bot_tokens(
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    match ctx
+        .data
+        .bot_token_repository
+        .list_bot_tokens(&chat.id)
+        .await
+    {
+        Ok(bot_tokens) => Response::from_json(&bot_tokens),
+        Err(e) => ctx.data.error(e.into()),
+    }
+}
+
+pub async fn handle_create_bot_token(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    let command: CreateBotTokenCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
         }
+    };
+
+    if command.name.trim().is_empty() {
+        return ctx.data.error(
+            ApiError::validation("Bot name must not be empty")
+                .with_field_error("name", "must not be empty"),
+        );
+    }
+
+    match ctx
+        .data
+        .bot_token_repository
+        .create_bot_token(chat.id, command.name.trim().to_string(), claims.sub)
+        .await
+    {
+        Ok(bot_token) => Response::from_json(&bot_token),
+        Err(e) => ctx.data.error(e.into()),
+    }
+}
 
-        match &ctx
+pub async fn handle_delete_bot_token(
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(token_id) = ctx.param("token_id") {
+        return match ctx
             .data
-            .auth_service
-            .verify_jwt_token(&password_header.unwrap().key)
+            .bot_token_repository
+            .delete_bot_token(&chat.id, token_id)
+            .await
         {
-            Ok(claims) => {
-                let url = req.url()?;
-                let mut new_url = url.clone();
-                new_url.set_query(Some(&format!("user_id={}", claims.sub)));
-                let mut new_req = Request::new(new_url.as_str(), req.method())?;
-                let _ = new_req.headers_mut()?.set("Upgrade", "websocket");
-
-                let object = ctx.durable_object("CHATROOM").unwrap();
-                let id = object.id_from_name(chat_id.as_str()).unwrap();
-                let stub = id.get_stub().unwrap();
-                let res = stub.fetch_with_request(new_req).await.unwrap();
-
-                return Ok(res);
-            }
-            Err(_) => return Response::error("Unauthorized", 401),
+            Ok(_) => Ok(Response::empty()?.with_status(204)),
+            Err(e) => ctx.data.error(e.into()),
         };
     }
 
-    Response::error("Bad Request", 400)
+    ctx.data
+        .error(ApiError::bad_request("Missing bot token id"))
 }
 
-pub fn verify_jwt(req: &Request, auth_service: &AuthenticationService) -> Result<Claims> {
-    tracing::info!("Verifying JWT");
-    let auth_header = req
-        .headers()
-        .get("Authorization")?
-        .ok_or(Error::RustError("No Authorization header".into()))?;
+pub async fn handle_list_webhooks(
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    match ctx.data.webhook_repository.list_webhooks(&chat.id).await {
+        Ok(webhooks) => Response::from_json(&webhooks),
+        Err(e) => ctx.data.error(e.into()),
+    }
+}
+
+pub async fn handle_register_webhook(
+    mut req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    let command: RegisterWebhookCommand = match req.json().await {
+        Ok(command) => command,
+        Err(_) => {
+            return ctx
+                .data
+                .error(ApiError::bad_request("Request body is not valid JSON"))
+        }
+    };
+
+    match Url::parse(&command.url) {
+        Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+        _ => {
+            return ctx.data.error(
+                ApiError::validation("Webhook url must be an absolute http(s) url")
+                    .with_field_error("url", "must be an absolute http(s) url"),
+            )
+        }
+    }
+
+    match ctx
+        .data
+        .webhook_repository
+        .add_webhook(Webhook::new(chat.id, command.url, claims.sub))
+        .await
+    {
+        Ok(webhook) => Response::from_json(&webhook),
+        Err(e) => ctx.data.error(e.into()),
+    }
+}
+
+pub async fn handle_delete_webhook(
+    _req: Request,
+    ctx: RouteContext<AppState>,
+    claims: Claims,
+) -> Result<Response> {
+    let chat = match get_owned_chat(&ctx, &claims).await? {
+        Ok(chat) => chat,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(webhook_id) = ctx.param("webhook_id") {
+        return match ctx
+            .data
+            .webhook_repository
+            .delete_webhook(&chat.id, webhook_id)
+            .await
+        {
+            Ok(_) => Ok(Response::empty()?.with_status(204)),
+            Err(e) => ctx.data.error(e.into()),
+        };
+    }
+
+    ctx.data.error(ApiError::bad_request("Missing webhook id"))
+}
+
+// Looks up the chat in the `chat_id` route parameter, returning the response to send instead
+// if it does not exist or the caller is neither its creator nor an admin.
+async fn get_owned_chat(
+    ctx: &RouteContext<AppState>,
+    claims: &Claims,
+) -> Result<std::result::Result<ChatDTO, Response>> {
+    let chat_id = match ctx.param("chat_id") {
+        Some(chat_id) => chat_id,
+        None => {
+            return Ok(Err(ctx
+                .data
+                .error(ApiError::bad_request("Missing chat id"))?))
+        }
+    };
+
+    match ctx.data.chat_repository.get_chat(chat_id).await {
+        Ok(chat) if can_manage_chat(claims, &chat) => Ok(Ok(chat)),
+        Ok(_) => Ok(Err(ctx.data.error(ApiError::forbidden())?)),
+        Err(e) => Ok(Err(ctx.data.error(e.into())?)),
+    }
+}
 
-    let token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or(Error::RustError("Invalid Authorization header".into()))?;
+fn can_manage_chat(claims: &Claims, chat: &ChatDTO) -> bool {
+    chat.created_by == claims.sub || claims.is_admin()
+}
 
-    let token = auth_service
-        .verify_jwt_token(token)
-        .map_err(|_e| Error::RustError("Failure verifying token".to_string()))?;
+// Looks up the chat in the `chat_id` route parameter, as long as the caller may see it. Private
+// chats are only open to whoever can manage them and the chat's own bots, everyone else is told
+// they do not exist.
+async fn get_visible_chat(
+    ctx: &RouteContext<AppState>,
+    claims: &Claims,
+) -> Result<std::result::Result<ChatDTO, Response>> {
+    let chat_id = match ctx.param("chat_id") {
+        Some(chat_id) => chat_id,
+        None => {
+            return Ok(Err(ctx
+                .data
+                .error(ApiError::bad_request("Missing chat id"))?))
+        }
+    };
+
+    match ctx.data.chat_repository.get_chat(chat_id).await {
+        Ok(chat) if can_view_chat(claims, &chat) => Ok(Ok(chat)),
+        Ok(_) => Ok(Err(ctx.data.error(ChatError::NotFound.into())?)),
+        Err(e) => Ok(Err(ctx.data.error(e.into())?)),
+    }
+}
 
-    Ok(token)
+fn can_view_chat(claims: &Claims, chat: &ChatDTO) -> bool {
+    !chat.private || can_manage_chat(claims, chat) || claims.is_bot()
 }
@@ -1,6 +1,16 @@
-use std::fmt::{Display, Formatter, Result};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result},
+};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+const MAX_POLL_OPTIONS: usize = 10;
+// Polls are kept in the Chatroom's storage for as long as the chat lives, so only this many are
+// kept at once.
+const MAX_POLLS: usize = 20;
 
 #[derive(Debug)]
 pub enum MessageTypes {
@@ -8,6 +18,10 @@ pub enum MessageTypes {
     MessageHistory,
     ChatroomEnded,
     ConnectionUpdate,
+    TopicUpdated,
+    CommandReply,
+    PollUpdated,
+    AuthRefreshed,
 }
 
 impl Display for MessageTypes {
@@ -18,7 +32,7 @@ impl Display for MessageTypes {
 
 #[derive(Deserialize)]
 pub struct IncomingMessageType {
-    pub message_type: String
+    pub message_type: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,49 +50,456 @@ impl<T> MessageWrapper<T> {
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+pub enum MessageKind {
+    #[default]
+    Text,
+    Action,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Message {
     contents: String,
     user: String,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    bot: bool,
+}
+
+impl Message {
+    pub fn text(user: String, contents: String) -> Self {
+        Message {
+            contents,
+            user,
+            kind: MessageKind::Text,
+            bot: false,
+        }
+    }
+
+    pub fn bot(user: String, contents: String) -> Self {
+        Message {
+            contents,
+            user,
+            kind: MessageKind::Text,
+            bot: true,
+        }
+    }
+
+    pub fn action(user: String, contents: String) -> Self {
+        Message {
+            contents,
+            user,
+            kind: MessageKind::Action,
+            bot: false,
+        }
+    }
+}
+
+// A message as sent by a WebSocket client. Who sent it and how it is shown are decided by the
+// Chatroom from the connection, never by the client.
+#[derive(Deserialize)]
+pub struct IncomingMessage {
+    pub contents: String,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ChatroomEnded {
-    chat_id: String
+    chat_id: String,
 }
 
 impl ChatroomEnded {
     pub fn new(chat_id: String) -> Self {
-        ChatroomEnded{
-            chat_id
-        }
+        ChatroomEnded { chat_id }
     }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ConnectionUpdate {
     connection_count: i32,
-    online_users: Vec<String>
+    online_users: Vec<String>,
 }
 
-impl ConnectionUpdate{
+impl ConnectionUpdate {
     pub fn new(connection_count: i32, online_users: Vec<String>) -> Self {
-        ConnectionUpdate{
+        ConnectionUpdate {
             connection_count,
-            online_users
+            online_users,
         }
     }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct MessageHistory {
-    history: Vec<Message>
+    history: Vec<Message>,
+    topic: Option<String>,
+    polls: Vec<PollUpdated>,
 }
 
 impl MessageHistory {
-    pub fn new(history: Vec<Message>) -> Self {
+    pub fn new(history: Vec<Message>, topic: Option<String>, polls: Vec<PollUpdated>) -> Self {
         MessageHistory {
-            history
+            history,
+            topic,
+            polls,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TopicUpdated {
+    topic: String,
+    updated_by: String,
+}
+
+impl TopicUpdated {
+    pub fn new(topic: String, updated_by: String) -> Self {
+        TopicUpdated { topic, updated_by }
+    }
+}
+
+// Sent only to the socket that issued a command, never broadcast.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CommandReply {
+    message: String,
+    is_error: bool,
+}
+
+impl CommandReply {
+    pub fn info(message: String) -> Self {
+        CommandReply {
+            message,
+            is_error: false,
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        CommandReply {
+            message,
+            is_error: true,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PollError {
+    #[error("A poll needs a question and between 2 and {MAX_POLL_OPTIONS} options")]
+    Invalid,
+    #[error("Poll not found")]
+    NotFound,
+    #[error("This poll is closed")]
+    Closed,
+    #[error("That option does not exist on this poll")]
+    InvalidOption,
+    #[error("Only the creator of a poll can close it")]
+    NotCreator,
+    #[error("There are already {MAX_POLLS} open polls, close one before creating another")]
+    TooManyOpen,
+}
+
+// Sent to the Chatroom's internal `messages` route by the HTTP message API, either by a bot or by
+// a client using the SSE transport.
+#[derive(Deserialize, Serialize)]
+pub struct PostedMessage {
+    pub user: String,
+    pub contents: String,
+    pub bot: bool,
+}
+
+// Sent to the Chatroom's internal `room` route when a chat's topic or expiry is changed through
+// `PATCH /api/chats/:chat_id`.
+#[derive(Deserialize, Serialize)]
+pub struct RoomSettings {
+    pub topic: Option<String>,
+    pub expiry_in_seconds: Option<u64>,
+    pub updated_by: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePoll {
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Vote {
+    pub poll_id: String,
+    pub option: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ClosePoll {
+    pub poll_id: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Poll {
+    id: String,
+    question: String,
+    options: Vec<String>,
+    created_by: String,
+    // Keyed by user so each user holds exactly one vote, which they can change.
+    votes: HashMap<String, usize>,
+    closed: bool,
+}
+
+impl Poll {
+    pub fn new(command: CreatePoll, created_by: String) -> std::result::Result<Self, PollError> {
+        let question = command.question.trim().to_string();
+        let options: Vec<String> = command
+            .options
+            .iter()
+            .map(|option| option.trim().to_string())
+            .filter(|option| !option.is_empty())
+            .collect();
+
+        if question.is_empty() || options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+            return Err(PollError::Invalid);
+        }
+
+        Ok(Poll {
+            id: Uuid::new_v4().to_string(),
+            question,
+            options,
+            created_by,
+            votes: HashMap::new(),
+            closed: false,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn vote(&mut self, user: String, option: usize) -> std::result::Result<(), PollError> {
+        if self.closed {
+            return Err(PollError::Closed);
+        }
+
+        if option >= self.options.len() {
+            return Err(PollError::InvalidOption);
+        }
+
+        self.votes.insert(user, option);
+
+        Ok(())
+    }
+
+    pub fn close(&mut self, user: &str) -> std::result::Result<(), PollError> {
+        if self.created_by != user {
+            return Err(PollError::NotCreator);
+        }
+
+        self.closed = true;
+
+        Ok(())
+    }
+
+    pub fn to_update(&self) -> PollUpdated {
+        let mut tallies = vec![0; self.options.len()];
+
+        for option in self.votes.values() {
+            if let Some(tally) = tallies.get_mut(*option) {
+                *tally += 1;
+            }
         }
+
+        PollUpdated {
+            poll_id: self.id.clone(),
+            question: self.question.clone(),
+            created_by: self.created_by.clone(),
+            options: self
+                .options
+                .iter()
+                .zip(tallies)
+                .map(|(option, votes)| PollOptionTally {
+                    option: option.clone(),
+                    votes,
+                })
+                .collect(),
+            closed: self.closed,
+        }
+    }
+}
+
+// Adds the poll to the chat's polls, dropping the oldest closed poll once there are too many to
+// keep. Polls are only ever appended, so the first closed poll is the oldest one.
+pub fn add_poll(polls: &mut Vec<Poll>, poll: Poll) -> std::result::Result<(), PollError> {
+    if polls.len() >= MAX_POLLS {
+        match polls.iter().position(|poll| poll.closed) {
+            Some(oldest_closed) => {
+                polls.remove(oldest_closed);
+            }
+            None => return Err(PollError::TooManyOpen),
+        }
+    }
+
+    polls.push(poll);
+
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PollOptionTally {
+    option: String,
+    votes: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PollUpdated {
+    poll_id: String,
+    question: String,
+    created_by: String,
+    options: Vec<PollOptionTally>,
+    closed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshAuth {
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AuthRefreshed {
+    expires_at: u64,
+}
+
+impl AuthRefreshed {
+    pub fn new(expires_at: u64) -> Self {
+        AuthRefreshed { expires_at }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll() -> Poll {
+        Poll::new(
+            CreatePoll {
+                question: "Lunch?".to_string(),
+                options: vec!["Pizza".to_string(), "Tacos".to_string()],
+            },
+            "jane".to_string(),
+        )
+        .unwrap()
+    }
+
+    fn tallies(poll: &Poll) -> Vec<u32> {
+        poll.to_update()
+            .options
+            .iter()
+            .map(|option| option.votes)
+            .collect()
+    }
+
+    #[test]
+    fn polls_need_a_question_and_at_least_two_options() {
+        let create = |question: &str, options: &[&str]| {
+            Poll::new(
+                CreatePoll {
+                    question: question.to_string(),
+                    options: options.iter().map(|option| option.to_string()).collect(),
+                },
+                "jane".to_string(),
+            )
+        };
+
+        assert!(matches!(
+            create(" ", &["Pizza", "Tacos"]),
+            Err(PollError::Invalid)
+        ));
+        assert!(matches!(
+            create("Lunch?", &["Pizza", " "]),
+            Err(PollError::Invalid)
+        ));
+        assert!(matches!(
+            create("Lunch?", &["Pizza"; MAX_POLL_OPTIONS + 1]),
+            Err(PollError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn votes_are_tallied_per_option() {
+        let mut poll = poll();
+
+        poll.vote("jane".to_string(), 0).unwrap();
+        poll.vote("john".to_string(), 1).unwrap();
+        poll.vote("joan".to_string(), 1).unwrap();
+
+        assert_eq!(tallies(&poll), vec![1, 2]);
+    }
+
+    #[test]
+    fn voting_again_changes_the_vote() {
+        let mut poll = poll();
+
+        poll.vote("john".to_string(), 0).unwrap();
+        poll.vote("john".to_string(), 1).unwrap();
+
+        assert_eq!(tallies(&poll), vec![0, 1]);
+    }
+
+    #[test]
+    fn votes_for_options_that_do_not_exist_are_rejected() {
+        let mut poll = poll();
+
+        assert!(matches!(
+            poll.vote("john".to_string(), 2),
+            Err(PollError::InvalidOption)
+        ));
+        assert_eq!(tallies(&poll), vec![0, 0]);
+    }
+
+    #[test]
+    fn only_the_creator_can_close_a_poll() {
+        let mut poll = poll();
+
+        assert!(matches!(poll.close("john"), Err(PollError::NotCreator)));
+        assert!(!poll.to_update().closed);
+
+        poll.close("jane").unwrap();
+        assert!(poll.to_update().closed);
+    }
+
+    #[test]
+    fn closed_polls_keep_their_votes_and_take_no_more() {
+        let mut poll = poll();
+
+        poll.vote("john".to_string(), 0).unwrap();
+        poll.close("jane").unwrap();
+
+        assert!(matches!(
+            poll.vote("joan".to_string(), 1),
+            Err(PollError::Closed)
+        ));
+        assert_eq!(tallies(&poll), vec![1, 0]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn the_oldest_closed_poll_is_dropped_once_there_are_too_many() {
+        let mut polls: Vec<Poll> = (0..MAX_POLLS).map(|_| poll()).collect();
+        polls[1].close("jane").unwrap();
+        polls[2].close("jane").unwrap();
+        let oldest_closed = polls[1].id().to_string();
+
+        let newest = poll();
+        let newest_id = newest.id().to_string();
+        add_poll(&mut polls, newest).unwrap();
+
+        assert_eq!(polls.len(), MAX_POLLS);
+        assert!(polls.iter().all(|poll| poll.id() != oldest_closed));
+        assert_eq!(polls.last().unwrap().id(), newest_id);
+    }
+
+    #[test]
+    fn polls_are_rejected_when_too_many_are_open() {
+        let mut polls: Vec<Poll> = (0..MAX_POLLS).map(|_| poll()).collect();
+
+        assert!(matches!(
+            add_poll(&mut polls, poll()),
+            Err(PollError::TooManyOpen)
+        ));
+        assert_eq!(polls.len(), MAX_POLLS);
+    }
+}
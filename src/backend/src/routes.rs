@@ -0,0 +1,317 @@
+use std::{future::Future, rc::Rc};
+
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use shared::{auth::Claims, errors::ApiError};
+use tracing::{info, warn};
+use worker::{Method, Request, Response, Result, RouteContext, Router};
+
+use crate::{AppState, QueryStringParameters};
+
+/// How a route authenticates its caller. Every route except a `Public` one is wrapped in a guard
+/// that rejects the request before the handler runs unless one of the credentials it accepts
+/// verifies, and hands the verified claims to the handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    // A JWT in the `Authorization: Bearer` header.
+    Bearer,
+    // Browsers cannot set headers when opening a WebSocket or EventSource, so as well as the
+    // Authorization header these routes accept a token offered as a `bearer.<token>`
+    // subprotocol, a single-use `ticket` from `POST /api/connect-ticket/:chat_id`, or the
+    // deprecated `key` query parameter while ALLOW_QUERY_STRING_TOKENS is enabled.
+    Connection,
+    // A JWT, or a bot token issued for the chat in the `chat_id` route parameter with
+    // `Authorization: Bot <token>`.
+    BearerOrBot,
+}
+
+/// A credential found on a request, before it has been verified.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Credential {
+    Bearer(String),
+    Bot(String),
+    Ticket(String),
+    QueryKey(String),
+}
+
+/// The parts of a request credentials can be offered in.
+#[derive(Default)]
+pub struct PresentedCredentials {
+    pub authorization: Option<String>,
+    pub subprotocols: Option<String>,
+    pub ticket: Option<String>,
+    pub key: Option<String>,
+}
+
+impl PresentedCredentials {
+    pub fn from_request(req: &Request) -> Self {
+        let query = req.query::<QueryStringParameters>().ok();
+
+        PresentedCredentials {
+            authorization: req.headers().get("Authorization").ok().flatten(),
+            subprotocols: req.headers().get("Sec-WebSocket-Protocol").ok().flatten(),
+            ticket: query.as_ref().and_then(|query| query.ticket.clone()),
+            key: query.and_then(|query| query.key),
+        }
+    }
+
+    fn bearer_header(&self) -> Option<Credential> {
+        self.authorization
+            .as_deref()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|token| Credential::Bearer(token.to_string()))
+    }
+}
+
+impl Access {
+    pub fn is_public(&self) -> bool {
+        *self == Access::Public
+    }
+
+    /// Picks the credential the guard will verify, or `None` if the request offers nothing this
+    /// route accepts.
+    pub fn credential(&self, presented: &PresentedCredentials) -> Option<Credential> {
+        match self {
+            Access::Public => None,
+            Access::Bearer => presented.bearer_header(),
+            Access::BearerOrBot => presented.bearer_header().or_else(|| {
+                presented
+                    .authorization
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bot "))
+                    .map(|token| Credential::Bot(token.to_string()))
+            }),
+            Access::Connection => presented
+                .bearer_header()
+                .or_else(|| {
+                    presented.subprotocols.as_deref().and_then(|subprotocols| {
+                        subprotocols
+                            .split(',')
+                            .find_map(|protocol| protocol.trim().strip_prefix("bearer."))
+                            .map(|token| Credential::Bearer(token.to_string()))
+                    })
+                })
+                .or_else(|| presented.ticket.clone().map(Credential::Ticket))
+                .or_else(|| presented.key.clone().map(Credential::QueryKey)),
+        }
+    }
+}
+
+type GuardedHandler = Rc<
+    dyn Fn(
+        Request,
+        RouteContext<AppState>,
+        Option<Claims>,
+    ) -> LocalBoxFuture<'static, Result<Response>>,
+>;
+
+pub struct RouteDefinition {
+    // `None` matches any method.
+    pub method: Option<Method>,
+    pub pattern: &'static str,
+    pub access: Access,
+    handler: GuardedHandler,
+}
+
+impl RouteDefinition {
+    // No backend route is public yet.
+    #[allow(dead_code)]
+    pub fn public<F, T>(method: Option<Method>, pattern: &'static str, handler: F) -> Self
+    where
+        F: Fn(Request, RouteContext<AppState>) -> T + 'static,
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        RouteDefinition {
+            method,
+            pattern,
+            access: Access::Public,
+            handler: Rc::new(move |req, ctx, _| handler(req, ctx).boxed_local()),
+        }
+    }
+
+    pub fn protected<F, T>(
+        method: Option<Method>,
+        pattern: &'static str,
+        access: Access,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Request, RouteContext<AppState>, Claims) -> T + 'static,
+        T: Future<Output = Result<Response>> + 'static,
+    {
+        RouteDefinition {
+            method,
+            pattern,
+            access,
+            handler: Rc::new(move |req, ctx, claims| match claims {
+                Some(claims) => handler(req, ctx, claims).boxed_local(),
+                None => async move { ctx.data.error(ApiError::unauthorized()) }.boxed_local(),
+            }),
+        }
+    }
+
+    pub fn register(self, router: Router<'static, AppState>) -> Router<'static, AppState> {
+        let access = self.access;
+        let handler = self.handler;
+
+        let guarded = move |req: Request, ctx: RouteContext<AppState>| {
+            let handler = handler.clone();
+
+            async move {
+                if access.is_public() {
+                    return handler(req, ctx, None).await;
+                }
+
+                match authenticate(access, &req, &ctx).await {
+                    Some(claims) => handler(req, ctx, Some(claims)).await,
+                    None => ctx.data.error(ApiError::unauthorized()),
+                }
+            }
+        };
+
+        match self.method {
+            Some(Method::Get) => router.get_async(self.pattern, guarded),
+            Some(Method::Post) => router.post_async(self.pattern, guarded),
+            Some(Method::Put) => router.put_async(self.pattern, guarded),
+            Some(Method::Patch) => router.patch_async(self.pattern, guarded),
+            Some(Method::Delete) => router.delete_async(self.pattern, guarded),
+            Some(Method::Head) => router.head_async(self.pattern, guarded),
+            Some(Method::Options) => router.options_async(self.pattern, guarded),
+            _ => router.on_async(self.pattern, guarded),
+        }
+    }
+}
+
+async fn authenticate(
+    access: Access,
+    req: &Request,
+    ctx: &RouteContext<AppState>,
+) -> Option<Claims> {
+    let credential = access.credential(&PresentedCredentials::from_request(req))?;
+
+    let claims = match credential {
+        Credential::Bearer(token) => ctx.data.auth_service.verify_jwt_token(&token).await.ok(),
+        Credential::Bot(token) => {
+            let chat_id = ctx.param("chat_id")?;
+
+            ctx.data
+                .bot_token_repository
+                .verify_bot_token(chat_id, &token)
+                .await
+                .map(|bot| Claims::for_bot(bot.name))
+        }
+        Credential::Ticket(ticket) => {
+            let chat_id = ctx.param("chat_id")?;
+
+            ctx.data.ticket_repository.redeem(&ticket, chat_id).await
+        }
+        Credential::QueryKey(key) => {
+            if !ctx.data.allow_query_string_tokens {
+                info!("Rejecting token passed in the query string");
+                return None;
+            }
+
+            warn!("Token passed in the deprecated key query parameter");
+            ctx.data.auth_service.verify_jwt_token(&key).await.ok()
+        }
+    }?;
+
+    // Bot tokens are revoked by deleting them, only tokens issued on login can be logged out.
+    if !claims.is_bot() && ctx.data.revocation_store.is_revoked(&claims).await {
+        info!("Rejecting revoked token");
+        return None;
+    }
+
+    Some(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+
+    fn presented(authorization: Option<&str>, subprotocols: Option<&str>) -> PresentedCredentials {
+        PresentedCredentials {
+            authorization: authorization.map(|value| value.to_string()),
+            subprotocols: subprotocols.map(|value| value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn every_non_public_route_rejects_anonymous_requests() {
+        let anonymous = PresentedCredentials::default();
+
+        for route in routes().iter().filter(|route| !route.access.is_public()) {
+            assert_eq!(
+                route.access.credential(&anonymous),
+                None,
+                "{:?} {} accepts anonymous requests",
+                route.method,
+                route.pattern
+            );
+        }
+    }
+
+    #[test]
+    fn every_route_is_declared_once() {
+        let routes = routes();
+
+        for (index, route) in routes.iter().enumerate() {
+            assert!(
+                !routes[index + 1..]
+                    .iter()
+                    .any(|other| other.method == route.method && other.pattern == route.pattern),
+                "{:?} {} is declared twice",
+                route.method,
+                route.pattern
+            );
+        }
+    }
+
+    #[test]
+    fn bearer_routes_only_accept_the_authorization_header() {
+        let ticket = PresentedCredentials {
+            ticket: Some("ticket".to_string()),
+            key: Some("token".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(Access::Bearer.credential(&ticket), None);
+        assert_eq!(
+            Access::Bearer.credential(&presented(Some("Bot token"), None)),
+            None
+        );
+        assert_eq!(
+            Access::Bearer.credential(&presented(None, Some("chat.json, bearer.token"))),
+            None
+        );
+        assert_eq!(
+            Access::Bearer.credential(&presented(Some("Bearer token"), None)),
+            Some(Credential::Bearer("token".to_string()))
+        );
+    }
+
+    #[test]
+    fn connection_routes_accept_a_subprotocol_token() {
+        assert_eq!(
+            Access::Connection.credential(&presented(None, Some("chat.json, bearer.token"))),
+            Some(Credential::Bearer("token".to_string()))
+        );
+        assert_eq!(
+            Access::Connection.credential(&presented(None, Some("chat.json"))),
+            None
+        );
+    }
+
+    #[test]
+    fn bot_tokens_are_only_accepted_where_declared() {
+        let bot = presented(Some("Bot token"), None);
+
+        assert_eq!(
+            Access::BearerOrBot.credential(&bot),
+            Some(Credential::Bot("token".to_string()))
+        );
+        assert_eq!(Access::Connection.credential(&bot), None);
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::auth::Claims;
+use tracing::{info, warn};
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use worker::{D1Database, Date};
+
+use crate::chats::ChatError;
+
+// Tickets only need to live long enough for the client to open its connection.
+const TICKET_LIFETIME_IN_SECONDS: u64 = 30;
+
+#[derive(Serialize)]
+pub struct ConnectTicketResponse {
+    pub ticket: String,
+    pub expires_in: u64,
+}
+
+struct ConnectTicket {
+    chat_id: String,
+    claims: Claims,
+    expires_at: u64,
+}
+
+// A ticket as stored in D1, with the claims serialized to JSON.
+#[derive(Deserialize)]
+struct StoredConnectTicket {
+    chat_id: String,
+    claims: String,
+    expires_at: u64,
+}
+
+fn hash_ticket(ticket: &str) -> String {
+    hex::encode(Sha256::digest(ticket.as_bytes()))
+}
+
+fn now_in_seconds() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+// Tickets are kept in D1 rather than KV, so that redeeming one is a single conditional delete
+// that only one request can win, wherever the requests are made from. Only their hash is stored.
+pub struct ConnectTicketRepository {
+    database: D1Database,
+}
+
+impl ConnectTicketRepository {
+    pub fn new(database: D1Database) -> Self {
+        ConnectTicketRepository { database }
+    }
+
+    /// Swaps a verified bearer token for a single-use ticket that can only open a connection to
+    /// the given chat.
+    pub async fn issue(
+        &self,
+        chat_id: String,
+        claims: Claims,
+    ) -> Result<ConnectTicketResponse, ChatError> {
+        let ticket = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let now = now_in_seconds();
+        let claims =
+            serde_json::to_string(&claims).map_err(|e| ChatError::Storage(e.to_string()))?;
+
+        // Tickets that were never redeemed are cleared out as new ones are issued.
+        let statements = vec![
+            self.database
+                .prepare(
+                    "DELETE FROM connect_tickets
+WHERE expires_at < ?1",
+                )
+                .bind(&[JsValue::from(now as f64)])?,
+            self.database
+                .prepare(
+                    "INSERT INTO connect_tickets
+            (ticket_hash, chat_id, claims, expires_at)
+            VALUES
+            (?1, ?2, ?3, ?4)",
+                )
+                .bind(&[
+                    JsValue::from(hash_ticket(&ticket)),
+                    JsValue::from(chat_id),
+                    JsValue::from(claims),
+                    JsValue::from((now + TICKET_LIFETIME_IN_SECONDS) as f64),
+                ])?,
+        ];
+
+        self.database.batch(statements).await?;
+
+        Ok(ConnectTicketResponse {
+            ticket,
+            expires_in: TICKET_LIFETIME_IN_SECONDS,
+        })
+    }
+
+    /// Returns the claims of the user the ticket was issued to. The ticket is deleted in the
+    /// same statement that reads it, whether or not it is valid, so it can never be used twice.
+    pub async fn redeem(&self, ticket: &str, chat_id: &str) -> Option<Claims> {
+        let stored_ticket = self
+            .database
+            .prepare(
+                "DELETE FROM connect_tickets
+WHERE ticket_hash = ?1
+RETURNING chat_id, claims, expires_at",
+            )
+            .bind(&[JsValue::from(hash_ticket(ticket))])
+            .ok()?
+            .first::<StoredConnectTicket>(None)
+            .await;
+
+        let stored_ticket = match stored_ticket {
+            Ok(stored_ticket) => stored_ticket?,
+            Err(e) => {
+                warn!("Failure redeeming connect ticket: {}", e);
+                return None;
+            }
+        };
+
+        let claims = match serde_json::from_str(&stored_ticket.claims) {
+            Ok(claims) => claims,
+            Err(e) => {
+                warn!("Failure reading connect ticket claims: {}", e);
+                return None;
+            }
+        };
+
+        ConnectTicket {
+            chat_id: stored_ticket.chat_id,
+            claims,
+            expires_at: stored_ticket.expires_at,
+        }
+        .claims_for(chat_id, now_in_seconds())
+    }
+}
+
+impl ConnectTicket {
+    // A ticket only opens a connection to the chat it was issued for, and only until it expires.
+    fn claims_for(self, chat_id: &str, now: u64) -> Option<Claims> {
+        if self.chat_id != chat_id {
+            info!("Connect ticket used for the wrong chat");
+            return None;
+        }
+
+        if self.expires_at < now {
+            info!("Connect ticket expired");
+            return None;
+        }
+
+        Some(self.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn ticket_for(chat_id: &str) -> ConnectTicket {
+        let claims = serde_json::from_value(serde_json::json!({
+            "sub": "jane",
+            "exp": NOW + 3600,
+            "jti": "jti",
+            "iss": "rusty-serverless-chat-authentication",
+            "aud": "rusty-serverless-chat",
+            "iat": NOW,
+            "nbf": NOW,
+            "roles": [],
+            "scopes": [],
+        }))
+        .unwrap();
+
+        ConnectTicket {
+            chat_id: chat_id.to_string(),
+            claims,
+            expires_at: NOW + TICKET_LIFETIME_IN_SECONDS,
+        }
+    }
+
+    #[test]
+    fn a_ticket_opens_its_own_chat_until_it_expires() {
+        let claims = ticket_for("chat-1").claims_for("chat-1", NOW);
+        assert_eq!(claims.map(|claims| claims.sub), Some("jane".to_string()));
+
+        let expires_at = NOW + TICKET_LIFETIME_IN_SECONDS;
+        assert!(ticket_for("chat-1")
+            .claims_for("chat-1", expires_at)
+            .is_some());
+    }
+
+    #[test]
+    fn an_expired_ticket_is_rejected() {
+        let expired = NOW + TICKET_LIFETIME_IN_SECONDS + 1;
+
+        assert!(ticket_for("chat-1").claims_for("chat-1", expired).is_none());
+    }
+
+    #[test]
+    fn a_ticket_for_another_chat_is_rejected() {
+        assert!(ticket_for("chat-1").claims_for("chat-2", NOW).is_none());
+    }
+}
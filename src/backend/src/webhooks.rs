@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use shared::events::ChatEvent;
+use tracing::warn;
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use worker::{D1Database, Queue};
+
+use crate::chats::ChatError;
+
+#[derive(Deserialize)]
+pub struct RegisterWebhookCommand {
+    pub url: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub chat_id: String,
+    pub url: String,
+    pub secret: String,
+    pub created_by: String,
+}
+
+impl Webhook {
+    pub fn new(chat_id: String, url: String, created_by: String) -> Self {
+        Webhook {
+            id: Uuid::new_v4().to_string(),
+            chat_id,
+            url,
+            secret: format!("whsec_{}", Uuid::new_v4().simple()),
+            created_by,
+        }
+    }
+}
+
+// The signing secret is only returned when a webhook is registered, never when listing.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WebhookDTO {
+    pub id: String,
+    pub chat_id: String,
+    pub url: String,
+}
+
+impl WebhookDTO {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookDTO {
+            id: webhook.id.clone(),
+            chat_id: webhook.chat_id.clone(),
+            url: webhook.url.clone(),
+        }
+    }
+}
+
+pub struct WebhookRepository {
+    database: D1Database,
+}
+
+impl WebhookRepository {
+    pub fn new(database: D1Database) -> Self {
+        WebhookRepository { database }
+    }
+
+    pub async fn list_webhooks(&self, chat_id: &str) -> Result<Vec<WebhookDTO>, ChatError> {
+        let webhooks = self
+            .database
+            .prepare(
+                "SELECT id, chat_id, url, secret, created_by
+FROM chat_webhooks
+WHERE chat_id = ?1",
+            )
+            .bind(&[JsValue::from(chat_id)])?
+            .all()
+            .await?
+            .results::<Webhook>()?;
+
+        Ok(webhooks.iter().map(WebhookDTO::from).collect())
+    }
+
+    pub async fn add_webhook(&self, webhook: Webhook) -> Result<Webhook, ChatError> {
+        self.database
+            .prepare(
+                "INSERT INTO chat_webhooks
+            (id, chat_id, url, secret, created_by)
+            VALUES
+            (?1, ?2, ?3, ?4, ?5)
+            RETURNING *;",
+            )
+            .bind(&[
+                JsValue::from(webhook.id),
+                JsValue::from(webhook.chat_id),
+                JsValue::from(webhook.url),
+                JsValue::from(webhook.secret),
+                JsValue::from(webhook.created_by),
+            ])?
+            .first::<Webhook>(None)
+            .await?
+            .ok_or_else(|| ChatError::Storage("Inserted webhook was not returned".to_string()))
+    }
+
+    pub async fn delete_webhook(&self, chat_id: &str, webhook_id: &str) -> Result<(), ChatError> {
+        self.database
+            .prepare(
+                "DELETE FROM chat_webhooks
+WHERE chat_id = ?1 AND id = ?2",
+            )
+            .bind(&[JsValue::from(chat_id), JsValue::from(webhook_id)])?
+            .run()
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub struct ChatEventPublisher {
+    queue: Option<Queue>,
+}
+
+impl ChatEventPublisher {
+    pub fn new(queue: Option<Queue>) -> Self {
+        ChatEventPublisher { queue }
+    }
+
+    pub async fn publish(&self, event: ChatEvent) {
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+
+        if let Err(e) = queue.send(&event).await {
+            warn!("Failure publishing {} event: {}", event.event_type, e);
+        }
+    }
+}
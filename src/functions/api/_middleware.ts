@@ -0,0 +1,48 @@
+import { PagesFunction } from "@cloudflare/workers-types/experimental";
+
+// Gives every API request an ID the workers include in their logs and error responses. A
+// worker that panics cannot respond itself, so failures that reach here without the JSON error
+// envelope are wrapped in it.
+export const onRequest: PagesFunction = async (context) => {
+	const requestId =
+		context.request.headers.get("X-Request-Id") ??
+		context.request.headers.get("cf-ray") ??
+		crypto.randomUUID();
+
+	const request = new Request(context.request);
+	request.headers.set("X-Request-Id", requestId);
+
+	try {
+		const response = await context.next(request);
+		const contentType = response.headers.get("Content-Type") ?? "";
+
+		if (response.status >= 500 && !contentType.startsWith("application/json")) {
+			return internalError(requestId);
+		}
+
+		return response;
+	} catch (e) {
+		console.error(`Request ${requestId} failed: ${e}`);
+
+		return internalError(requestId);
+	}
+}
+
+function internalError(requestId: string): Response {
+	return new Response(
+		JSON.stringify({
+			error: {
+				code: "internal_error",
+				message: "Something went wrong",
+				request_id: requestId,
+			},
+		}),
+		{
+			status: 500,
+			headers: {
+				"Content-Type": "application/json",
+				"X-Request-Id": requestId,
+			},
+		}
+	);
+}
@@ -24,4 +24,13 @@ resource "cloudflare_hyperdrive_config" "users_db" {
 resource "cloudflare_queue" "user_notification_queue" {
   account_id = var.cloudflare_account_id
   name       = "user-notifications"
-}
\ No newline at end of file
+}
+resource "cloudflare_queue" "chat_events_queue" {
+  account_id = var.cloudflare_account_id
+  name       = "chat-events"
+}
+
+resource "cloudflare_queue" "webhook_deliveries_queue" {
+  account_id = var.cloudflare_account_id
+  name       = "webhook-deliveries"
+}
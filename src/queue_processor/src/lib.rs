@@ -1,36 +1,24 @@
 use sendgrid::{Destination, Mail, SGClient};
 use serde::{Deserialize, Serialize};
-use tracing_subscriber::{
-    fmt::{format::Pretty, time::UtcTime},
-    layer::SubscriberExt,
-    util::SubscriberInitExt,
+use shared::{
+    errors::set_panic_hook,
+    events::{UserNotification, UserNotificationTypes},
+    telemetry,
 };
-use tracing_web::{performance_layer, MakeConsoleWriter};
 use worker::*;
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct UserDTO {
-    username: String,
-    email_address: String,
-}
-
 #[event(start)]
 fn start() {
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .json()
-        .with_ansi(false) // Only partially supported across JavaScript runtimes
-        .with_timer(UtcTime::rfc_3339()) // std::time is not available in browsers
-        .with_writer(MakeConsoleWriter); // write events to the console
-    let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(perf_layer)
-        .init();
+    telemetry::init();
 }
 
 #[event(queue)]
-pub async fn main(message_batch: MessageBatch<UserDTO>, env: Env, _: Context) -> Result<()> {
-    console_error_panic_hook::set_once();
+pub async fn main(
+    message_batch: MessageBatch<UserNotification>,
+    env: Env,
+    _: Context,
+) -> Result<()> {
+    set_panic_hook();
 
     let api_key = env.secret("EMAIlL_API_KEY")?.to_string();
     let from_address = env.secret("FROM_ADDRESS")?.to_string();
@@ -42,15 +30,23 @@ pub async fn main(message_batch: MessageBatch<UserDTO>, env: Env, _: Context) ->
 
     for message in message_batch.messages()? {
         tracing::info!(
-            "Got message {:?}, with id {} and timestamp: {}",
-            message.body(),
+            "Got {:?} message with id {} and timestamp: {}",
+            message.body().notification_type,
             message.id(),
             message.timestamp().to_string(),
         );
-        tracing::info!("Username is {:?}", message.body().username);
-        tracing::info!("Email is {:?}", message.body().email_address);
 
-        let email_body = format!("<h1>Thankyou for joining us!</h1><p>Hey {},</p><p>It's great to have you on this journey into the wonderful world of serverless technologies, Rust and Cloudflare</p><p>This message is coming to you all the way from a Cloudflare queue. Cool, right?</p><p>Thanks again,</p><p>James</p>", &message.body().username);
+        let (subject, email_body) = match email_content(message.body()) {
+            Some(content) => content,
+            None => {
+                tracing::warn!(
+                    "{:?} email is missing its link, dropping it",
+                    message.body().notification_type
+                );
+                message.ack();
+                continue;
+            }
+        };
 
         let mail_info = Mail::new()
             .add_to(Destination {
@@ -58,7 +54,7 @@ pub async fn main(message_batch: MessageBatch<UserDTO>, env: Env, _: Context) ->
                 name: &message.body().username,
             })
             .add_from(&from_address)
-            .add_subject("Welcome to Serverless Chats")
+            .add_subject(subject)
             .add_html(&email_body)
             .add_x_smtpapi(&x_smtpapi);
 
@@ -73,13 +69,29 @@ pub async fn main(message_batch: MessageBatch<UserDTO>, env: Env, _: Context) ->
     Ok(())
 }
 
+fn email_content(notification: &UserNotification) -> Option<(&'static str, String)> {
+    match notification.notification_type {
+        UserNotificationTypes::Welcome => Some(("Welcome to Serverless Chats", format!("<h1>Thankyou for joining us!</h1><p>Hey {},</p><p>It's great to have you on this journey into the wonderful world of serverless technologies, Rust and Cloudflare</p><p>This message is coming to you all the way from a Cloudflare queue. Cool, right?</p><p>Thanks again,</p><p>James</p>", &notification.username))),
+        UserNotificationTypes::VerifyEmail => {
+            let verification_url = notification.link.as_ref()?;
+
+            Some(("Verify your email address", format!("<h1>Confirm your email address</h1><p>Hey {},</p><p>Thanks for signing up to Serverless Chats. Please <a href=\"{}\">verify your email address</a> to finish creating your account, the link is valid for 24 hours.</p><p>If you did not sign up you can ignore this email.</p><p>Thanks,</p><p>James</p>", &notification.username, verification_url)))
+        }
+        UserNotificationTypes::PasswordReset => {
+            let reset_url = notification.link.as_ref()?;
+
+            Some(("Reset your password", format!("<h1>Reset your password</h1><p>Hey {},</p><p>Someone asked to reset the password for your Serverless Chats account. <a href=\"{}\">Choose a new password</a> within the next hour, the link can only be used once.</p><p>If it wasn't you, you can ignore this email and your password will stay the same.</p><p>Thanks,</p><p>James</p>", &notification.username, reset_url)))
+        }
+    }
+}
+
 pub struct AppState {
     queue: Queue,
 }
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    console_error_panic_hook::set_once();
+    set_panic_hook();
     let user_notifications_queue = env.queue("USER_NOTIFICATIONS")?;
 
     Router::with_data(AppState {
@@ -100,9 +112,11 @@ pub async fn handle_test(req: Request, ctx: RouteContext<AppState>) -> Result<Re
             let _sendres = ctx
                 .data
                 .queue
-                .send(UserDTO {
+                .send(UserNotification {
+                    notification_type: UserNotificationTypes::Welcome,
                     username: query.username,
                     email_address: query.email_address,
+                    link: None,
                 })
                 .await;
 
@@ -0,0 +1,432 @@
+use std::cell::RefCell;
+
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{AlgorithmParameters, Jwk},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use worker::{Date, Fetcher, Response};
+
+pub use jsonwebtoken::jwk::JwkSet;
+
+// Access tokens are valid for an hour after they are issued, clients use their refresh token
+// to get a new one.
+pub const ACCESS_TOKEN_LIFETIME_IN_SECONDS: usize = 3600;
+
+// Every token names the authentication worker as its issuer and the chat services as its
+// audience, tokens minted for anything else are rejected.
+pub const TOKEN_ISSUER: &str = "rusty-serverless-chat-authentication";
+pub const TOKEN_AUDIENCE: &str = "rusty-serverless-chat";
+
+// Email verification links are signed with the same keys, but for their own audience so one
+// can never be used as an access token.
+pub const EMAIL_VERIFICATION_AUDIENCE: &str = "rusty-serverless-chat-email-verification";
+pub const EMAIL_VERIFICATION_TOKEN_LIFETIME_IN_SECONDS: usize = 24 * 60 * 60;
+
+// Clocks on different machines drift, so time based claims are allowed this much slack.
+pub(crate) const CLOCK_SKEW_IN_SECONDS: u64 = 60;
+
+// Admins can manage every chat, moderators can use moderator commands in every chatroom.
+pub const ADMIN_ROLE: &str = "admin";
+pub const MODERATOR_ROLE: &str = "moderator";
+
+// Where the authentication worker publishes the public half of its signing keys.
+pub const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+// Workers that verify tokens keep the published keys for this long before fetching them again.
+const KEY_SET_TTL_IN_MILLIS: u64 = 5 * 60 * 1000;
+// A token signed with a key we have not seen triggers a refetch, so a newly rotated key is
+// picked up straight away, but no more often than this.
+const KEY_SET_MIN_REFRESH_IN_MILLIS: u64 = 30 * 1000;
+
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("Failure generating token: {0}")]
+    Generate(String),
+    #[error("Token is invalid: {0}")]
+    Invalid(String),
+    #[error("Token was signed with an unknown key: {0}")]
+    UnknownKey(String),
+    #[error("Failure loading signing keys: {0}")]
+    KeySet(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    // Identifies the token so it can be revoked on logout.
+    pub jti: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: usize,
+    pub nbf: usize,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+    // Whether the request was made with a bot token rather than a JWT. Only `for_bot` sets it,
+    // it is never read from a token, so no role or claim a user's token carries can make them a
+    // bot.
+    #[serde(skip)]
+    bot: bool,
+}
+
+impl Claims {
+    // Bot tokens do not expire, they are valid until deleted.
+    pub fn for_bot(name: String) -> Self {
+        Claims {
+            sub: name,
+            exp: 0,
+            jti: String::new(),
+            iss: String::new(),
+            aud: String::new(),
+            iat: 0,
+            nbf: 0,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            bot: true,
+        }
+    }
+
+    pub fn is_bot(&self) -> bool {
+        self.bot
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has_role(ADMIN_ROLE)
+    }
+}
+
+/// Carried by the link in a verification email. The address is included so a link sent before
+/// the user changed their email cannot verify the new one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    pub sub: String,
+    pub email: String,
+    pub exp: usize,
+    pub iss: String,
+    pub aud: String,
+    pub iat: usize,
+    pub nbf: usize,
+}
+
+// Only asymmetric keys are accepted, so holding the published keys never lets a worker mint
+// tokens itself.
+fn algorithm_for(jwk: &Jwk) -> Result<Algorithm, TokenError> {
+    match jwk.algorithm {
+        AlgorithmParameters::OctetKeyPair(_) => Ok(Algorithm::EdDSA),
+        AlgorithmParameters::RSA(_) => Ok(Algorithm::RS256),
+        _ => Err(TokenError::KeySet(format!(
+            "Key {} is not an Ed25519 or RSA key",
+            jwk.common.key_id.as_deref().unwrap_or_default()
+        ))),
+    }
+}
+
+/// The private key the authentication worker signs tokens with. Its `kid` must be one of the
+/// published keys, which also decides whether it is used for EdDSA or RS256.
+pub struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+impl SigningKey {
+    pub fn from_pem(kid: String, pem: &str, key_set: &JwkSet) -> Result<Self, TokenError> {
+        let jwk = key_set
+            .find(&kid)
+            .ok_or_else(|| TokenError::UnknownKey(kid.clone()))?;
+        let algorithm = algorithm_for(jwk)?;
+
+        let key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(pem.as_bytes()),
+            _ => EncodingKey::from_rsa_pem(pem.as_bytes()),
+        }
+        .map_err(|e| TokenError::KeySet(e.to_string()))?;
+
+        Ok(SigningKey {
+            kid,
+            algorithm,
+            key,
+        })
+    }
+}
+
+struct CachedKeySet {
+    key_set: JwkSet,
+    fetched_at: u64,
+}
+
+thread_local! {
+    // Kept for the life of the isolate, so most requests verify without fetching the keys.
+    static CACHED_KEY_SET: RefCell<Option<CachedKeySet>> = const { RefCell::new(None) };
+}
+
+enum KeySource {
+    // The keys the authentication worker publishes itself.
+    Published(JwkSet),
+    // Fetched from the authentication worker's JWKS endpoint over a service binding.
+    Remote(Fetcher),
+}
+
+/// Issues and verifies the JWTs the authentication worker hands out on login and every other
+/// worker accepts. Several keys can be published at once, so a new signing key can be
+/// published before it is used and an old one kept until the tokens it signed have expired.
+pub struct AuthenticationService {
+    keys: KeySource,
+    signing_key: Option<SigningKey>,
+}
+
+impl AuthenticationService {
+    pub fn issuer(signing_key: SigningKey, key_set: JwkSet) -> Self {
+        AuthenticationService {
+            keys: KeySource::Published(key_set),
+            signing_key: Some(signing_key),
+        }
+    }
+
+    pub fn verifier(authentication_service: Fetcher) -> Self {
+        AuthenticationService {
+            keys: KeySource::Remote(authentication_service),
+            signing_key: None,
+        }
+    }
+
+    /// The public keys to publish, only available to the worker that signs tokens.
+    pub fn published_keys(&self) -> Option<&JwkSet> {
+        match &self.keys {
+            KeySource::Published(key_set) => Some(key_set),
+            KeySource::Remote(_) => None,
+        }
+    }
+
+    /// Signs an access token for the user, carrying the roles and scopes from their record.
+    pub fn generate_token_for(
+        &self,
+        username: String,
+        roles: Vec<String>,
+        scopes: Vec<String>,
+    ) -> Result<String, TokenError> {
+        let issued_at = (Date::now().as_millis() / 1000) as usize;
+
+        let claims = Claims {
+            sub: username,
+            exp: issued_at + ACCESS_TOKEN_LIFETIME_IN_SECONDS,
+            jti: Uuid::new_v4().to_string(),
+            iss: TOKEN_ISSUER.to_string(),
+            aud: TOKEN_AUDIENCE.to_string(),
+            iat: issued_at,
+            nbf: issued_at,
+            roles,
+            scopes,
+            bot: false,
+        };
+
+        self.sign(&claims)
+    }
+
+    pub fn generate_email_verification_token(
+        &self,
+        username: String,
+        email_address: String,
+    ) -> Result<String, TokenError> {
+        let issued_at = (Date::now().as_millis() / 1000) as usize;
+
+        let claims = EmailVerificationClaims {
+            sub: username,
+            email: email_address,
+            exp: issued_at + EMAIL_VERIFICATION_TOKEN_LIFETIME_IN_SECONDS,
+            iss: TOKEN_ISSUER.to_string(),
+            aud: EMAIL_VERIFICATION_AUDIENCE.to_string(),
+            iat: issued_at,
+            nbf: issued_at,
+        };
+
+        self.sign(&claims)
+    }
+
+    pub async fn verify_jwt_token(&self, token: &str) -> Result<Claims, TokenError> {
+        tracing::info!("Verifying JWT");
+
+        let claims: Claims = self.decode_token(token, TOKEN_AUDIENCE).await?;
+        check_issued_at(claims.iat)?;
+
+        Ok(claims)
+    }
+
+    pub async fn verify_email_verification_token(
+        &self,
+        token: &str,
+    ) -> Result<EmailVerificationClaims, TokenError> {
+        let claims: EmailVerificationClaims = self
+            .decode_token(token, EMAIL_VERIFICATION_AUDIENCE)
+            .await?;
+        check_issued_at(claims.iat)?;
+
+        Ok(claims)
+    }
+
+    fn sign<T: Serialize>(&self, claims: &T) -> Result<String, TokenError> {
+        let signing_key = self
+            .signing_key
+            .as_ref()
+            .ok_or_else(|| TokenError::Generate("No signing key configured".to_string()))?;
+
+        let mut header = Header::new(signing_key.algorithm);
+        header.kid = Some(signing_key.kid.clone());
+
+        encode(&header, claims, &signing_key.key).map_err(|e| {
+            error!("Failure generating token: {}", e);
+            TokenError::Generate(e.to_string())
+        })
+    }
+
+    async fn decode_token<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        audience: &str,
+    ) -> Result<T, TokenError> {
+        let kid = decode_header(token)
+            .map_err(|e| TokenError::Invalid(e.to_string()))?
+            .kid
+            .ok_or_else(|| TokenError::Invalid("Token has no kid".to_string()))?;
+
+        let jwk = self.key_for(&kid).await?;
+        let algorithm = algorithm_for(&jwk)?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| TokenError::KeySet(e.to_string()))?;
+
+        decode::<T>(token, &key, &validation(algorithm, audience))
+            .map(|token_data| token_data.claims)
+            .map_err(|e| {
+                error!("{}", e);
+                TokenError::Invalid(e.to_string())
+            })
+    }
+
+    async fn key_for(&self, kid: &str) -> Result<Jwk, TokenError> {
+        let service = match &self.keys {
+            KeySource::Published(key_set) => {
+                return key_set
+                    .find(kid)
+                    .cloned()
+                    .ok_or_else(|| TokenError::UnknownKey(kid.to_string()))
+            }
+            KeySource::Remote(service) => service,
+        };
+
+        let now = Date::now().as_millis();
+
+        let (cached_key, age) = CACHED_KEY_SET.with(|cached| match &*cached.borrow() {
+            Some(cached) => (
+                cached.key_set.find(kid).cloned(),
+                Some(now.saturating_sub(cached.fetched_at)),
+            ),
+            None => (None, None),
+        });
+
+        match (&cached_key, age) {
+            (Some(jwk), Some(age)) if age < KEY_SET_TTL_IN_MILLIS => return Ok(jwk.clone()),
+            (None, Some(age)) if age < KEY_SET_MIN_REFRESH_IN_MILLIS => {
+                return Err(TokenError::UnknownKey(kid.to_string()))
+            }
+            _ => {}
+        }
+
+        info!("Fetching signing keys");
+        let key_set = match fetch_key_set(service).await {
+            Ok(key_set) => key_set,
+            // Keep verifying with the keys we already have rather than failing every request.
+            Err(e) => {
+                warn!("{}", e);
+                return cached_key.ok_or(e);
+            }
+        };
+        let jwk = key_set.find(kid).cloned();
+
+        CACHED_KEY_SET.with(|cached| {
+            *cached.borrow_mut() = Some(CachedKeySet {
+                key_set,
+                fetched_at: now,
+            })
+        });
+
+        jwk.ok_or_else(|| TokenError::UnknownKey(kid.to_string()))
+    }
+}
+
+// The library only checks that `iat` is present, not that it is in the past.
+fn check_issued_at(issued_at: usize) -> Result<(), TokenError> {
+    let now = Date::now().as_millis() / 1000;
+
+    if issued_at as u64 > now + CLOCK_SKEW_IN_SECONDS {
+        return Err(TokenError::Invalid(
+            "Token was issued in the future".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn validation(algorithm: Algorithm, audience: &str) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[TOKEN_ISSUER]);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["sub", "exp", "iss", "aud", "iat", "nbf"]);
+    validation.validate_nbf = true;
+    validation.leeway = CLOCK_SKEW_IN_SECONDS;
+    validation
+}
+
+async fn fetch_key_set(service: &Fetcher) -> Result<JwkSet, TokenError> {
+    let response = service
+        .fetch(format!("https://authentication{}", JWKS_PATH), None)
+        .await
+        .map_err(|e| TokenError::KeySet(e.to_string()))?;
+    let mut response =
+        Response::try_from(response).map_err(|e| TokenError::KeySet(e.to_string()))?;
+
+    if response.status_code() != 200 {
+        return Err(TokenError::KeySet(format!(
+            "JWKS endpoint responded with {}",
+            response.status_code()
+        )));
+    }
+
+    response
+        .json::<JwkSet>()
+        .await
+        .map_err(|e| TokenError::KeySet(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_bot_token_claims_are_bots() {
+        assert!(Claims::for_bot("deploy-bot".to_string()).is_bot());
+
+        let claims: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "jane",
+            "exp": 0,
+            "jti": "jti",
+            "iss": TOKEN_ISSUER,
+            "aud": TOKEN_AUDIENCE,
+            "iat": 0,
+            "nbf": 0,
+            "roles": ["bot"],
+            "scopes": [],
+            "bot": true,
+        }))
+        .unwrap();
+
+        assert!(!claims.is_bot());
+    }
+}
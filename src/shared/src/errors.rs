@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+use worker::{Request, Response};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    ValidationFailed,
+    UpgradeRequired,
+    TooManyRequests,
+    InternalError,
+    ServiceUnavailable,
+}
+
+impl ErrorCode {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::MethodNotAllowed => 405,
+            ErrorCode::Conflict => 409,
+            ErrorCode::ValidationFailed => 422,
+            ErrorCode::UpgradeRequired => 426,
+            ErrorCode::TooManyRequests => 429,
+            ErrorCode::InternalError => 500,
+            ErrorCode::ServiceUnavailable => 503,
+        }
+    }
+
+    fn default_message(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "Bad Request",
+            ErrorCode::Unauthorized => "Unauthorized",
+            ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::NotFound => "Not Found",
+            ErrorCode::MethodNotAllowed => "Method Not Allowed",
+            ErrorCode::Conflict => "Conflict",
+            ErrorCode::ValidationFailed => "Validation Failed",
+            ErrorCode::UpgradeRequired => "Upgrade Required",
+            ErrorCode::TooManyRequests => "Too Many Requests",
+            ErrorCode::InternalError => "Something went wrong",
+            ErrorCode::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    fn from_status_code(status_code: u16) -> Self {
+        match status_code {
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::Forbidden,
+            404 => ErrorCode::NotFound,
+            405 => ErrorCode::MethodNotAllowed,
+            409 => ErrorCode::Conflict,
+            422 => ErrorCode::ValidationFailed,
+            426 => ErrorCode::UpgradeRequired,
+            429 => ErrorCode::TooManyRequests,
+            503 => ErrorCode::ServiceUnavailable,
+            400..=499 => ErrorCode::BadRequest,
+            _ => ErrorCode::InternalError,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// The error every API route returns, serialized as
+/// `{ "error": { "code", "message", "request_id", "field_errors" } }`.
+#[derive(Debug)]
+pub struct ApiError {
+    code: ErrorCode,
+    message: String,
+    field_errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: &'a str,
+    request_id: &'a str,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    field_errors: &'a [FieldError],
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            field_errors: Vec::new(),
+        }
+    }
+
+    pub fn from_code(code: ErrorCode) -> Self {
+        ApiError::new(code, code.default_message())
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::BadRequest, message)
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError::from_code(ErrorCode::Unauthorized)
+    }
+
+    pub fn forbidden() -> Self {
+        ApiError::from_code(ErrorCode::Forbidden)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::ValidationFailed, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::ServiceUnavailable, message)
+    }
+
+    pub fn internal() -> Self {
+        ApiError::from_code(ErrorCode::InternalError)
+    }
+
+    pub fn with_field_error(
+        mut self,
+        field: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        self.field_errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn field_errors(&self) -> &[FieldError] {
+        &self.field_errors
+    }
+
+    pub fn into_response(self, request_id: &str) -> worker::Result<Response> {
+        let envelope = ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code,
+                message: &self.message,
+                request_id,
+                field_errors: &self.field_errors,
+            },
+        };
+
+        let mut response = Response::from_json(&envelope)?.with_status(self.code.status_code());
+        response.headers_mut().set(REQUEST_ID_HEADER, request_id)?;
+
+        Ok(response)
+    }
+}
+
+/// Uses the request ID given by the Pages proxy or Cloudflare's ray ID, so log lines can be
+/// matched to the error a client saw, generating one if neither is present.
+pub fn request_id(req: &Request) -> String {
+    let is_valid = |id: &String| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LENGTH
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    [REQUEST_ID_HEADER, "cf-ray"]
+        .iter()
+        .filter_map(|header| req.headers().get(header).ok().flatten())
+        .find(is_valid)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Applied to whatever a worker's router returns. Errors that escaped a handler become an
+/// `internal_error`, and plain text errors, such as the router's own 404 and 405 responses or
+/// those from a Durable Object, are wrapped in the envelope.
+pub async fn finalize_response(
+    result: worker::Result<Response>,
+    request_id: &str,
+) -> worker::Result<Response> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            error!(request_id, "Unhandled error: {}", e);
+            return ApiError::internal().into_response(request_id);
+        }
+    };
+
+    let status_code = response.status_code();
+    let is_json = response
+        .headers()
+        .get("Content-Type")
+        .ok()
+        .flatten()
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+    if status_code >= 400 && !is_json {
+        let message = response.text().await.unwrap_or_default();
+        let code = ErrorCode::from_status_code(status_code);
+
+        let error = match message.trim() {
+            "" => ApiError::from_code(code),
+            message => ApiError::new(code, message),
+        };
+
+        return error.into_response(request_id);
+    }
+
+    // Responses proxied from a Durable Object have immutable headers.
+    let _ = response.headers_mut().set(REQUEST_ID_HEADER, request_id);
+
+    Ok(response)
+}
+
+/// Logs panics as structured errors as well as to the console. A panic aborts the worker, so
+/// the Pages proxy turns the failed request into an `internal_error` envelope.
+pub fn set_panic_hook() {
+    static SET_HOOK: std::sync::Once = std::sync::Once::new();
+
+    SET_HOOK.call_once(|| {
+        std::panic::set_hook(Box::new(|info| {
+            error!("Worker panicked: {}", info);
+            console_error_panic_hook::hook(info);
+        }));
+    });
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use worker::Date;
+
+// Returned when a user registers.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserDTO {
+    pub username: String,
+    pub email_address: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserNotificationTypes {
+    // Messages queued before verification existed have no type and are welcome emails.
+    #[default]
+    Welcome,
+    VerifyEmail,
+    PasswordReset,
+}
+
+// Sent by the authentication worker onto the `user-notifications` queue, and consumed by the
+// queue processor to send the email. A user is asked to verify their address when they
+// register, and welcomed once they have. Verification and password reset emails carry the
+// link the user follows.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserNotification {
+    #[serde(default)]
+    pub notification_type: UserNotificationTypes,
+    pub username: String,
+    pub email_address: String,
+    #[serde(
+        default,
+        alias = "verification_url",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub link: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ChatEventTypes {
+    MessageCreated,
+    ChatEnded,
+    UserJoined,
+}
+
+impl ChatEventTypes {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatEventTypes::MessageCreated => "message.created",
+            ChatEventTypes::ChatEnded => "chat.ended",
+            ChatEventTypes::UserJoined => "user.joined",
+        }
+    }
+}
+
+// Published by the Chatroom onto the `chat-events` queue and fanned out to each registered
+// webhook by the webhook-delivery worker.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChatEvent {
+    pub event_id: String,
+    pub event_type: String,
+    pub chat_id: String,
+    pub occurred_at: u64,
+    pub data: serde_json::Value,
+}
+
+impl ChatEvent {
+    pub fn new<T: Serialize>(event_type: ChatEventTypes, chat_id: String, data: &T) -> Self {
+        ChatEvent {
+            event_id: Uuid::new_v4().to_string(),
+            event_type: event_type.as_str().to_string(),
+            chat_id,
+            occurred_at: Date::now().as_millis(),
+            data: serde_json::to_value(data).unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,6 @@
+// Code shared by every worker in the workspace.
+pub mod auth;
+pub mod errors;
+pub mod events;
+pub mod revocation;
+pub mod telemetry;
@@ -0,0 +1,117 @@
+use tracing::warn;
+use worker::{kv::KvStore, Date};
+
+use crate::auth::{Claims, ACCESS_TOKEN_LIFETIME_IN_SECONDS, CLOCK_SKEW_IN_SECONDS};
+
+// TTL in workers must be at least 60 seconds.
+const MIN_TTL_IN_SECONDS: u64 = 60;
+
+/// Access tokens are stateless, so logging out records them in a KV denylist that every worker
+/// checks after verifying a token's signature. Entries only live until the token would have
+/// expired anyway, including the leeway verifiers allow for clock skew.
+pub struct RevocationStore {
+    store: KvStore,
+}
+
+impl RevocationStore {
+    pub fn new(store: KvStore) -> Self {
+        RevocationStore { store }
+    }
+
+    fn token_key(jti: &str) -> String {
+        format!("revoked-token:{}", jti)
+    }
+
+    fn user_key(username: &str) -> String {
+        format!("revoked-user:{}", username)
+    }
+
+    fn now_in_seconds() -> u64 {
+        Date::now().as_millis() / 1000
+    }
+
+    pub async fn revoke_token(&self, claims: &Claims) -> worker::Result<()> {
+        let ttl = token_ttl(claims.exp as u64, Self::now_in_seconds());
+
+        self.store
+            .put(&Self::token_key(&claims.jti), claims.exp)?
+            .expiration_ttl(ttl)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revokes every access token issued to the user before now. Tokens are issued with whole
+    /// seconds, so one issued in the same second, such as on the login straight after a password
+    /// reset, is still accepted.
+    pub async fn revoke_all_for(&self, username: &str) -> worker::Result<()> {
+        self.store
+            .put(&Self::user_key(username), Self::now_in_seconds())?
+            .expiration_ttl(ACCESS_TOKEN_LIFETIME_IN_SECONDS as u64 + CLOCK_SKEW_IN_SECONDS)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    // A token is treated as revoked if the denylist cannot be read, rather than letting a
+    // logged out token through.
+    pub async fn is_revoked(&self, claims: &Claims) -> bool {
+        self.is_token_revoked(&claims.sub, &claims.jti, claims.iat as u64)
+            .await
+    }
+
+    /// The same check as `is_revoked`, for holders of a token that only kept the claims needed
+    /// for it, such as a Chatroom checking its open connections.
+    pub async fn is_token_revoked(&self, username: &str, jti: &str, issued_at: u64) -> bool {
+        match self.store.get(&Self::token_key(jti)).text().await {
+            Ok(Some(_)) => return true,
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failure reading revoked tokens: {:?}", e);
+                return true;
+            }
+        }
+
+        match self
+            .store
+            .get(&Self::user_key(username))
+            .json::<u64>()
+            .await
+        {
+            Ok(Some(revoked_at)) => issued_at < revoked_at,
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Failure reading revoked users: {:?}", e);
+                true
+            }
+        }
+    }
+}
+
+// How long a revoked token has to be remembered for, until even a verifier whose clock is behind
+// will reject it as expired.
+fn token_ttl(expires_at: u64, now: u64) -> u64 {
+    (expires_at + CLOCK_SKEW_IN_SECONDS)
+        .saturating_sub(now)
+        .max(MIN_TTL_IN_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn revoked_tokens_are_kept_past_their_expiry_by_the_clock_skew() {
+        assert_eq!(token_ttl(NOW + 3600, NOW), 3600 + CLOCK_SKEW_IN_SECONDS);
+    }
+
+    #[test]
+    fn revoked_tokens_are_kept_for_the_minimum_ttl() {
+        assert_eq!(token_ttl(NOW - 3600, NOW), MIN_TTL_IN_SECONDS);
+        assert_eq!(token_ttl(NOW - 30, NOW), MIN_TTL_IN_SECONDS);
+    }
+}
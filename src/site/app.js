@@ -6,7 +6,9 @@ let chatroomId = localStorage.getItem(chatRoomIdLocalStorageKey);
 let api_root = "";
 let ws_root = "";
 let messages = [];
+let polls = {};
 let ws = undefined;
+let eventSource = undefined;
 
 $(document).ready(function () {
   isConnected = false;
@@ -45,14 +47,100 @@ $.ajaxSetup({
   }
 });
 
-function logout() {
+// Revokes the session on the server before forgetting it, or every session of the user with
+// `allSessions`.
+function logout(allSessions) {
+  const jwt = localStorage.getItem('jwt');
+
+  if (!jwt) {
+    clearSession();
+    return;
+  }
+
+  fetch(`${api_root}/api/logout`, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json',
+      Authorization: 'Bearer ' + jwt,
+    },
+    body: JSON.stringify({
+      refresh_token: localStorage.getItem('refresh_token'),
+      all_sessions: allSessions === true,
+    }),
+  }).finally(clearSession);
+}
+
+function clearSession() {
   localStorage.removeItem('jwt');
+  localStorage.removeItem('jwt_expires_at');
+  localStorage.removeItem('refresh_token');
   window.location.href = '/login';
 }
 
+// An expired access token is swapped for a new one with the refresh token and the request that
+// failed is sent again. `this` is the settings of the failed request.
 function handleUnauthorized() {
-  localStorage.removeItem('jwt');
-  window.location.href = '/login';
+  const request = this;
+
+  if (request.retriedAfterRefresh) {
+    clearSession();
+    return;
+  }
+
+  refreshSession(function () {
+    request.retriedAfterRefresh = true;
+    $.ajax(request);
+  }, clearSession);
+}
+
+// Refresh tokens are single use, so the new one replaces the stored token every time.
+function refreshSession(onRefreshed, onFailed) {
+  const refreshToken = localStorage.getItem('refresh_token');
+
+  if (!refreshToken) {
+    onFailed();
+    return;
+  }
+
+  fetch(`${api_root}/api/token/refresh`, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ refresh_token: refreshToken }),
+  })
+    .then((response) => (response.ok ? response.json() : Promise.reject(response.status)))
+    .then((tokens) => {
+      localStorage.setItem('jwt', tokens.token);
+      localStorage.setItem('jwt_expires_at', Date.now() + tokens.expires_in * 1000);
+      localStorage.setItem('refresh_token', tokens.refresh_token);
+      onRefreshed(tokens);
+    })
+    .catch(() => onFailed());
+}
+
+// The chatroom closes a socket once its token expires, so the session is refreshed a minute
+// before then and the new token handed to the chatroom.
+function scheduleSessionRefresh() {
+  if (!localStorage.getItem('refresh_token')) {
+    return;
+  }
+
+  const expiresAt = Number(localStorage.getItem('jwt_expires_at') || 0);
+  const delay = Math.max(expiresAt - Date.now() - 60 * 1000, 0);
+
+  setTimeout(function () {
+    refreshSession(function (tokens) {
+      if (ws !== undefined && ws.readyState === WebSocket.OPEN) {
+        ws.send(
+          JSON.stringify({
+            message_type: "RefreshAuth",
+            message: { token: tokens.token },
+          })
+        );
+      }
+
+      scheduleSessionRefresh();
+    }, clearSession);
+  }, delay);
 }
 
 function checkForChatIdQueryParam() {
@@ -91,7 +179,17 @@ function sendmessage() {
     message_type: "NewMessage",
   };
 
-  ws.send(JSON.stringify(data));
+  if (eventSource !== undefined) {
+    $.ajax({
+      url: `${api_root}/api/chats/${chatroomId}/messages`,
+      method: "POST",
+      contentType: "application/json",
+      data: JSON.stringify({ contents: messageContents }),
+    });
+  } else {
+    ws.send(JSON.stringify(data));
+  }
+
   document.getElementById("message").value = "";
 }
 
@@ -109,37 +207,44 @@ function updateConnectionStatus() {
   }
 }
 
+// Swap the JWT for a short-lived, single-use ticket so it never appears in a URL.
+function requestConnectTicket(onTicket) {
+  $.ajax({
+    url: `${api_root}/api/connect-ticket/${chatroomId}`,
+    method: "POST",
+    success: function (response) {
+      onTicket(response.ticket);
+    },
+  });
+}
+
 function connectWebsockets() {
-  ws = new WebSocket(
-    `${ws_root}/api/connect/${chatroomId}?key=${localStorage.getItem('jwt')}`
-  );
+  requestConnectTicket(openWebsocket);
+}
+
+function openWebsocket(ticket) {
+  ws = new WebSocket(`${ws_root}/api/connect/${chatroomId}?ticket=${ticket}`);
 
   ws.onopen = () => {
     isConnected = true;
     updateConnectionStatus();
+    scheduleSessionRefresh();
     localStorage.setItem(usernameLocalStorageKey, username);
     localStorage.setItem(chatRoomIdLocalStorageKey, chatroomId);
   };
 
   ws.onmessage = (message) => {
-    const jsonMessageData = JSON.parse(message.data);
-    switch (jsonMessageData.message_type) {
-      case "NewMessage":
-        handleNewMessage(jsonMessageData);
-        break;
-      case "ConnectionUpdate":
-        handleConnectionUpdateMessage(jsonMessageData);
-        break;
-      case "ChatroomEnded":
-        handleChatroomEndedMessage();
-        break;
-      case "MessageHistory":
-        handleMessageHistoryMessage(jsonMessageData);
-        break;
-    }
+    handleRoomEvent(message.data);
   };
 
   ws.onclose = (e) => {
+    // Some networks block WebSocket upgrades, fall back to Server-Sent Events if the
+    // socket never opened.
+    if (!isConnected) {
+      requestConnectTicket(connectEventSource);
+      return;
+    }
+
     isConnected = false;
     updateConnectionStatus();
   };
@@ -149,6 +254,55 @@ function connectWebsockets() {
   };
 }
 
+function connectEventSource(ticket) {
+  eventSource = new EventSource(
+    `${api_root}/api/chats/${chatroomId}/events?ticket=${ticket}`
+  );
+
+  eventSource.onopen = () => {
+    isConnected = true;
+    updateConnectionStatus();
+  };
+
+  eventSource.onmessage = (message) => {
+    handleRoomEvent(message.data);
+  };
+
+  eventSource.onerror = (e) => {
+    console.log(e);
+    eventSource.close();
+    isConnected = false;
+    updateConnectionStatus();
+  };
+}
+
+function handleRoomEvent(data) {
+  const jsonMessageData = JSON.parse(data);
+  switch (jsonMessageData.message_type) {
+    case "NewMessage":
+      handleNewMessage(jsonMessageData);
+      break;
+    case "ConnectionUpdate":
+      handleConnectionUpdateMessage(jsonMessageData);
+      break;
+    case "ChatroomEnded":
+      handleChatroomEndedMessage();
+      break;
+    case "MessageHistory":
+      handleMessageHistoryMessage(jsonMessageData);
+      break;
+    case "TopicUpdated":
+      handleTopicUpdatedMessage(jsonMessageData);
+      break;
+    case "CommandReply":
+      handleCommandReplyMessage(jsonMessageData);
+      break;
+    case "PollUpdated":
+      handlePollUpdatedMessage(jsonMessageData.message);
+      break;
+  }
+}
+
 function leaveRoom() {
   window.location = "/chats";
 }
@@ -196,9 +350,99 @@ function handleMessageHistoryMessage(jsonMessageData) {
 
   messages = jsonMessageData.message.history;
 
+  updateTopic(jsonMessageData.message.topic);
+  jsonMessageData.message.polls.forEach(handlePollUpdatedMessage);
+  refreshMessages();
+}
+
+function handlePollUpdatedMessage(poll) {
+  polls[poll.poll_id] = poll;
+
+  refreshPolls();
+}
+
+function vote(pollId, option) {
+  ws.send(
+    JSON.stringify({
+      message: {
+        poll_id: pollId,
+        option: option,
+      },
+      message_type: "Vote",
+    })
+  );
+}
+
+function closePoll(pollId) {
+  ws.send(
+    JSON.stringify({
+      message: {
+        poll_id: pollId,
+      },
+      message_type: "ClosePoll",
+    })
+  );
+}
+
+function refreshPolls() {
+  const pollsDiv = document.getElementById("polls");
+  pollsDiv.innerHTML = "";
+
+  Object.values(polls).forEach((poll) => {
+    var pollElement = document.createElement("article");
+    var questionElement = document.createElement("strong");
+    questionElement.innerText = poll.closed
+      ? `${poll.question} (closed)`
+      : poll.question;
+    pollElement.appendChild(questionElement);
+
+    poll.options.forEach((option, index) => {
+      var optionElement = document.createElement("div");
+      optionElement.innerText = `${option.option}: ${option.votes} `;
+
+      if (!poll.closed) {
+        var button = document.createElement("button");
+        button.innerText = "Vote";
+        button.onclick = function () {
+          vote(poll.poll_id, index);
+        };
+        optionElement.appendChild(button);
+      }
+
+      pollElement.appendChild(optionElement);
+    });
+
+    if (!poll.closed && poll.created_by === username) {
+      var closeButton = document.createElement("button");
+      closeButton.innerText = "Close poll";
+      closeButton.onclick = function () {
+        closePoll(poll.poll_id);
+      };
+      pollElement.appendChild(closeButton);
+    }
+
+    pollsDiv.appendChild(pollElement);
+  });
+}
+
+function handleTopicUpdatedMessage(jsonMessageData) {
+  updateTopic(jsonMessageData.message.topic);
+}
+
+function handleCommandReplyMessage(jsonMessageData) {
+  messages.push({
+    user: "System",
+    contents: jsonMessageData.message.message,
+  });
+
   refreshMessages();
 }
 
+function updateTopic(topic) {
+  const topicElement = document.getElementById("topic");
+  topicElement.innerText = topic ? `Topic: ${topic}` : "";
+}
+
 function refreshMessages() {
   const messagesDiv = document.getElementById("messages");
   messagesDiv.innerHTML = "";
@@ -210,10 +454,21 @@ function refreshMessages() {
       user = "You";
     }
 
+    if (message.bot) {
+      user = `${user} [bot]`;
+    }
+
     var element = document.createElement("div");
-    element.appendChild(
-      document.createTextNode(`${user}: ${message.contents}`)
-    );
+
+    if (message.kind === "Action") {
+      element.appendChild(
+        document.createTextNode(`* ${user} ${message.contents}`)
+      );
+    } else {
+      element.appendChild(
+        document.createTextNode(`${user}: ${message.contents}`)
+      );
+    }
     messagesDiv.appendChild(element);
   });
 }
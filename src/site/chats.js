@@ -16,19 +16,84 @@ $.ajaxSetup({
   }
 });
 
-function logout() {
+// Revokes the session on the server before forgetting it, or every session of the user with
+// `allSessions`.
+function logout(allSessions) {
+  const jwt = localStorage.getItem('jwt');
+
+  if (!jwt) {
+    clearSession();
+    return;
+  }
+
+  fetch(`${api_root}/api/logout`, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json',
+      Authorization: 'Bearer ' + jwt,
+    },
+    body: JSON.stringify({
+      refresh_token: localStorage.getItem('refresh_token'),
+      all_sessions: allSessions === true,
+    }),
+  }).finally(clearSession);
+}
+
+function clearSession() {
   localStorage.removeItem('jwt');
+  localStorage.removeItem('jwt_expires_at');
+  localStorage.removeItem('refresh_token');
   window.location.href = '/login';
 }
 
+// An expired access token is swapped for a new one with the refresh token and the request that
+// failed is sent again. `this` is the settings of the failed request.
 function handleUnauthorized() {
-  localStorage.removeItem('jwt');
-  window.location.href = '/login';
+  const request = this;
+
+  if (request.retriedAfterRefresh) {
+    clearSession();
+    return;
+  }
+
+  refreshSession(function () {
+    request.retriedAfterRefresh = true;
+    $.ajax(request);
+  }, clearSession);
+}
+
+// Refresh tokens are single use, so the new one replaces the stored token every time.
+function refreshSession(onRefreshed, onFailed) {
+  const refreshToken = localStorage.getItem('refresh_token');
+
+  if (!refreshToken) {
+    onFailed();
+    return;
+  }
+
+  fetch(`${api_root}/api/token/refresh`, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ refresh_token: refreshToken }),
+  })
+    .then((response) => (response.ok ? response.json() : Promise.reject(response.status)))
+    .then((tokens) => {
+      localStorage.setItem('jwt', tokens.token);
+      localStorage.setItem('jwt_expires_at', Date.now() + tokens.expires_in * 1000);
+      localStorage.setItem('refresh_token', tokens.refresh_token);
+      onRefreshed(tokens);
+    })
+    .catch(() => onFailed());
 }
 
 function createChat() {
   const name = document.getElementById("chat_name").value;
   const chatPassword = document.getElementById("chat_password").value;
+  const tags = document
+    .getElementById("chat_tags")
+    .value.split(",")
+    .map((tag) => tag.trim())
+    .filter((tag) => tag.length > 0);
 
   if (name.length <= 0){
     alert('Name must not be empty');
@@ -48,6 +113,7 @@ function createChat() {
     JSON.stringify({
       name: name,
       password: chatPassword,
+      tags: tags,
     })
   );
   xhr.onload = () => {
@@ -59,6 +125,8 @@ function createChat() {
       localStorage.setItem("chatroom_id", data.id);
 
       window.location = '/';
+    } else if (xhr.status == 409 || xhr.status == 422) {
+      alert(JSON.parse(xhr.response).error.message);
     } else {
       console.log(`Error: ${xhr.status}`);
     }
@@ -72,9 +140,12 @@ function joinChat(chat_id) {
 }
 
 function refreshData() {
-  // Load all chats
+  // Load all chats, or those matching the search box
+  const search = document.getElementById("chat_search").value.trim();
+  const query = search.length > 0 ? `?q=${encodeURIComponent(search)}` : "";
+
   var xhr = new XMLHttpRequest();
-  xhr.open("GET", `${api_root}/api/chats`, true);
+  xhr.open("GET", `${api_root}/api/chats${query}`, true);
   xhr.setRequestHeader("Content-Type", "application/json");
   xhr.setRequestHeader("Authorization", 'Bearer ' + localStorage.getItem('jwt'));
   xhr.send();
@@ -85,7 +156,7 @@ function refreshData() {
 
       const data = JSON.parse(xhr.response);
       console.log(data);
-      data.forEach((chat) => {
+      data.chats.forEach((chat) => {
         const chatId = chat.id;
         const chatName = chat.name;
 
@@ -93,6 +164,9 @@ function refreshData() {
         var tableCellElement = document.createElement("td");
         tableCellElement.innerText = chatName;
 
+        var tagsCellElement = document.createElement("td");
+        tagsCellElement.innerText = (chat.tags || []).join(", ");
+
         var button = document.createElement("button");
         button.innerText = "Join Chat";
         button.onclick = function () {
@@ -103,6 +177,7 @@ function refreshData() {
         hrefElement.appendChild(button);
 
         rowElement.appendChild(tableCellElement);
+        rowElement.appendChild(tagsCellElement);
         rowElement.appendChild(hrefElement);
 
         tableBodyElement.appendChild(rowElement);
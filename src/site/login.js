@@ -10,10 +10,21 @@ function login() {
         success: function(response) {
             localStorage.setItem('username', username);
             localStorage.setItem('jwt', response.token);
+            localStorage.setItem('jwt_expires_at', Date.now() + response.expires_in * 1000);
+            localStorage.setItem('refresh_token', response.refresh_token);
             window.location.href = '/chats';
         },
         error: function(xhr, status, error) {
-            alert('Login failed: ' + error);
+            const message = xhr.responseJSON && xhr.responseJSON.error
+                ? xhr.responseJSON.error.message
+                : error;
+            alert('Login failed: ' + message);
         }
     });
-}
\ No newline at end of file
+}
+
+$(function() {
+    if (new URLSearchParams(window.location.search).get('email_verified') === 'true') {
+        alert('Your email address is verified, you can now login.');
+    }
+});
\ No newline at end of file
@@ -0,0 +1,46 @@
+const resetToken = new URLSearchParams(window.location.search).get('token');
+
+$(function() {
+    if (resetToken) {
+        $('#forgotForm').attr('hidden', true);
+        $('#resetForm').removeAttr('hidden');
+    }
+});
+
+function forgotPassword() {
+    const username = $('#username').val();
+
+    $.ajax({
+        url: '/api/password/forgot',
+        method: 'POST',
+        contentType: 'application/json',
+        data: JSON.stringify({ username }),
+        success: function(response) {
+            alert(response.message);
+        },
+        error: function(xhr, status, error) {
+            alert('Request failed: ' + error);
+        }
+    });
+}
+
+function resetPassword() {
+    const password = $('#password').val();
+
+    $.ajax({
+        url: '/api/password/reset',
+        method: 'POST',
+        contentType: 'application/json',
+        data: JSON.stringify({ token: resetToken, password }),
+        success: function() {
+            alert('Your password has been reset. Please login.');
+            window.location.href = '/login';
+        },
+        error: function(xhr, status, error) {
+            const message = xhr.responseJSON && xhr.responseJSON.error
+                ? xhr.responseJSON.error.message
+                : error;
+            alert('Reset failed: ' + message);
+        }
+    });
+}
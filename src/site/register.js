@@ -9,7 +9,7 @@ function register() {
         contentType: 'application/json',
         data: JSON.stringify({ username, password, email }),
         success: function(response) {
-            alert('Registration successful. Please login.');
+            alert('Registration successful. Please follow the link we have emailed you to verify your address, then login.');
             window.location.href = '/login';
         },
         error: function(xhr, status, error) {
@@ -0,0 +1,236 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use shared::{
+    errors::set_panic_hook,
+    events::{ChatEvent, ChatEventTypes},
+    telemetry,
+};
+use tracing::{info, warn};
+use wasm_bindgen::JsValue;
+use worker::*;
+
+// Deliveries are retried with exponential backoff: 10s, 20s, 40s, 80s, 160s.
+const MAX_DELIVERY_ATTEMPTS: u32 = 6;
+const BASE_RETRY_DELAY_SECONDS: u32 = 10;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct WebhookDelivery {
+    webhook_id: String,
+    event: ChatEvent,
+    attempt: u32,
+}
+
+// This worker consumes both the `chat-events` queue and its own `webhook-deliveries` queue.
+// The two message shapes are distinguished by their fields rather than the queue name.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum QueueMessage {
+    Delivery(WebhookDelivery),
+    Event(ChatEvent),
+}
+
+#[derive(Deserialize)]
+struct Webhook {
+    id: String,
+    url: String,
+    secret: String,
+}
+
+#[event(start)]
+fn start() {
+    telemetry::init();
+}
+
+#[event(queue)]
+pub async fn main(message_batch: MessageBatch<QueueMessage>, env: Env, _: Context) -> Result<()> {
+    set_panic_hook();
+
+    let database = env.d1("CHAT_METADATA")?;
+    let deliveries_queue = env.queue("WEBHOOK_DELIVERIES")?;
+
+    for message in message_batch.messages()? {
+        let result = match message.body() {
+            QueueMessage::Event(event) => fan_out(&database, &deliveries_queue, event).await,
+            QueueMessage::Delivery(delivery) => {
+                deliver(&database, &deliveries_queue, delivery).await
+            }
+        };
+
+        match result {
+            Ok(_) => message.ack(),
+            Err(e) => {
+                warn!("Failure processing message {}: {}", message.id(), e);
+                message.retry();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn fan_out(database: &D1Database, deliveries_queue: &Queue, event: &ChatEvent) -> Result<()> {
+    let webhooks = database
+        .prepare(
+            "SELECT id, url, secret
+FROM chat_webhooks
+WHERE chat_id = ?1",
+        )
+        .bind(&[JsValue::from(&event.chat_id)])?
+        .all()
+        .await?
+        .results::<Webhook>()?;
+
+    info!(
+        "Fanning out {} event for chat {} to {} webhooks",
+        event.event_type,
+        event.chat_id,
+        webhooks.len()
+    );
+
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let deliveries: Vec<WebhookDelivery> = webhooks
+        .into_iter()
+        .map(|webhook| WebhookDelivery {
+            webhook_id: webhook.id,
+            event: event.clone(),
+            attempt: 0,
+        })
+        .collect();
+
+    deliveries_queue.send_batch(deliveries).await
+}
+
+async fn deliver(
+    database: &D1Database,
+    deliveries_queue: &Queue,
+    delivery: &WebhookDelivery,
+) -> Result<()> {
+    let webhook = database
+        .prepare(
+            "SELECT id, url, secret
+FROM chat_webhooks
+WHERE id = ?1",
+        )
+        .bind(&[JsValue::from(&delivery.webhook_id)])?
+        .first::<Webhook>(None)
+        .await?;
+
+    let webhook = match webhook {
+        Some(webhook) => webhook,
+        None => {
+            info!("Webhook {} no longer exists, skipping", delivery.webhook_id);
+            return Ok(());
+        }
+    };
+
+    let retrying = match send_signed(&webhook, &delivery.event).await {
+        Ok(status) if (200..300).contains(&status) => {
+            info!(
+                "Delivered {} to webhook {}",
+                delivery.event.event_id, webhook.id
+            );
+            false
+        }
+        result => {
+            match result {
+                Ok(status) => warn!("Webhook {} responded with {}", webhook.id, status),
+                Err(e) => warn!("Failure calling webhook {}: {}", webhook.id, e),
+            }
+
+            schedule_retry(deliveries_queue, delivery).await?
+        }
+    };
+
+    // The backend leaves a deleted chat's webhooks in place so they can be told it ended, they
+    // are removed once that is done with.
+    if !retrying && delivery.event.event_type == ChatEventTypes::ChatEnded.as_str() {
+        remove_webhook(database, &webhook.id).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_webhook(database: &D1Database, webhook_id: &str) -> Result<()> {
+    info!("Removing webhook {} of an ended chat", webhook_id);
+
+    database
+        .prepare(
+            "DELETE FROM chat_webhooks
+WHERE id = ?1",
+        )
+        .bind(&[JsValue::from(webhook_id)])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+// Returns whether the delivery will be attempted again.
+async fn schedule_retry(deliveries_queue: &Queue, delivery: &WebhookDelivery) -> Result<bool> {
+    let attempt = delivery.attempt + 1;
+
+    if attempt >= MAX_DELIVERY_ATTEMPTS {
+        warn!(
+            "Giving up on delivering {} to webhook {} after {} attempts",
+            delivery.event.event_id, delivery.webhook_id, attempt
+        );
+        return Ok(false);
+    }
+
+    let delay_seconds = BASE_RETRY_DELAY_SECONDS * 2u32.pow(delivery.attempt);
+
+    deliveries_queue
+        .send(
+            MessageBuilder::new(WebhookDelivery {
+                webhook_id: delivery.webhook_id.clone(),
+                event: delivery.event.clone(),
+                attempt,
+            })
+            .delay_seconds(delay_seconds)
+            .build(),
+        )
+        .await?;
+
+    Ok(true)
+}
+
+async fn send_signed(webhook: &Webhook, event: &ChatEvent) -> Result<u16> {
+    let payload = serde_json::to_string(event)?;
+    let timestamp = Date::now().as_millis() / 1000;
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("X-Webhook-Id", &webhook.id)?;
+    headers.set("X-Webhook-Event", &event.event_type)?;
+    headers.set("X-Webhook-Timestamp", &timestamp.to_string())?;
+    headers.set(
+        "X-Webhook-Signature",
+        &format!("sha256={}", sign(&webhook.secret, timestamp, &payload)),
+    )?;
+
+    let request = Request::new_with_init(
+        &webhook.url,
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(JsValue::from_str(&payload))),
+    )?;
+
+    let response = Fetch::Request(request).send().await?;
+
+    Ok(response.status_code())
+}
+
+// Receivers verify a delivery by computing HMAC-SHA256 over `{timestamp}.{body}` with the secret
+// returned when the webhook was registered, and rejecting stale timestamps to prevent replays.
+fn sign(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}